@@ -0,0 +1,58 @@
+//! Guards that keep compression from being turned into an amplifier: responses are only
+//! compressed below a size budget, and gzip input is never expanded past what it claims
+//! to hold or past what deflate can legitimately produce.
+
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use axum::body::HttpBody;
+use axum::http::header;
+use tower_http::compression::Predicate;
+
+/// Deflate tops out just above 1032:1, so any stream expanding further is crafted.
+pub const MAX_DECOMPRESSION_RATIO: u64 = 1100;
+
+/// Operations cut short by these guards; reported on `/health/status`.
+#[derive(Default)]
+pub struct AmplificationCounters {
+    pub compression_skipped: AtomicU64,
+    pub decompression_aborted: AtomicU64,
+}
+
+/// Uncompressed size of a streamed response whose length isn't in its headers, left in the
+/// response extensions for `CompressionBudget`.
+#[derive(Clone, Copy)]
+pub struct PayloadSize(pub u64);
+
+/// Compresses only responses known to be at most `max_bytes` (`COMPRESSION_MAX_BYTES`).
+#[derive(Clone)]
+pub struct CompressionBudget {
+    pub max_bytes: u64,
+    pub counters: Arc<AmplificationCounters>,
+}
+
+impl Predicate for CompressionBudget {
+    fn should_compress<B: HttpBody>(&self, response: &axum::http::Response<B>) -> bool {
+        let size = response.extensions().get::<PayloadSize>().map(|s| s.0)
+            .or_else(|| response.headers().get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok()))
+            .or_else(|| response.body().size_hint().exact());
+        // Unknown sizes are treated as over budget.
+        let within = size.is_some_and(|n| n <= self.max_bytes);
+        if !within { self.counters.compression_skipped.fetch_add(1, Ordering::Relaxed); }
+        within
+    }
+}
+
+/// Gunzips `compressed_len` bytes of `input` into `out`, refusing to write more than
+/// `expected` bytes or more than `MAX_DECOMPRESSION_RATIO` times the input.
+/// Fails unless exactly `expected` bytes come out.
+pub fn bounded_gunzip(input: impl Read, compressed_len: u64, expected: u64, out: &mut impl Write, counters: &AmplificationCounters) -> std::io::Result<u64> {
+    let limit = expected.min(compressed_len.saturating_mul(MAX_DECOMPRESSION_RATIO));
+    let mut decoder = flate2::read::GzDecoder::new(input).take(limit + 1);
+    let n = std::io::copy(&mut decoder, out)?;
+    if n > limit || n != expected {
+        counters.decompression_aborted.fetch_add(1, Ordering::Relaxed);
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("解压后大小异常: 期望 {} 字节", expected)));
+    }
+    Ok(n)
+}
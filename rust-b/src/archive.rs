@@ -0,0 +1,265 @@
+//! Portable bucket archives: one NDJSON header line per file, immediately followed by
+//! exactly `size` bytes of file content, closed by an `{"end": true}` line. Content-addressed
+//! names carry their `hash`, and each one is followed by its blob.
+
+use std::fs;
+use std::path::Path;
+
+use axum::{body::Body, extract::{Path as AxPath, Query, State}, http::{HeaderMap, StatusCode, header}, response::IntoResponse};
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+use crate::amplify::PayloadSize;
+use crate::cas::{self, commit_blob, is_content_hash, link_name, upload_path};
+use crate::checksum::sha256_file;
+use crate::cold;
+use crate::handlers::{Quota, payload_too_large, register_location};
+use crate::prune::BucketWriteGuard;
+use crate::meta::{BucketConfig, Metadata, has_bucket_config, load_bucket_config, load_file_metadata, original_key_of, save_bucket_config, save_file_metadata, save_original_name};
+use crate::state::AppState;
+use crate::util::{is_hidden, is_plain_name, is_safe_key, rand_u32, join_key, original_key, split_key, validate_bucket_name};
+
+const FORMAT: &str = "fileio-export";
+const CONTENT_TYPE: &str = "application/x-fileio-export";
+/// Upper bound on a single header line so a bad stream can't make us buffer without limit.
+const MAX_LINE: u64 = 64 * 1024;
+
+#[derive(Serialize, Deserialize)]
+struct EntryHeader {
+    name: String,
+    size: u64,
+    /// Set for content-addressed names; the bytes that follow are the blob with this hash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+    /// The key a plain file was written under; archives from before it was recorded lack it.
+    #[serde(rename = "originalName", default, skip_serializing_if = "Option::is_none")]
+    original_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Metadata::is_empty")]
+    metadata: Metadata,
+}
+
+/// `(key, size)` of every visible file in the bucket, walking into visible folders.
+/// Demoted files are included with their original size.
+fn bucket_files(bucket_dir: &Path) -> Vec<(String, u64)> {
+    let mut files = Vec::new();
+    cold::walk_bucket(bucket_dir, |entry| { files.push((entry.key().to_string(), entry.size())); true });
+    files.sort();
+    files
+}
+
+pub async fn export_bucket(State(state): State<AppState>, AxPath(bucket): AxPath<String>) -> impl IntoResponse {
+    let Some(bucket_dir) = state.resolve_bucket_path(&bucket) else { return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"储存桶名称不合法"}))).into_response() };
+    if !bucket_dir.is_dir() { return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"储存桶不存在"}))).into_response(); }
+    // Content bytes about to be streamed, so the compression budget can judge the response.
+    // (key, content hash, size)
+    let mut entries: Vec<(String, Option<String>, u64)> = bucket_files(&bucket_dir).into_iter().map(|(key, size)| (key, None, size)).collect();
+    if state.content_addressed {
+        for (name, hash) in cas::names(&bucket_dir) {
+            if let Ok(m) = fs::metadata(cas::blob_path(&bucket_dir, &hash)) { entries.push((name, Some(hash), m.len())); }
+        }
+    }
+    let payload: u64 = entries.iter().map(|(_, _, size)| size).sum();
+    let (mut tx, rx) = tokio::io::duplex(64 * 1024);
+    let name = bucket.clone();
+    tokio::spawn(async move {
+        let write = async {
+            let config = load_bucket_config(&bucket_dir);
+            tx.write_all(format!("{}\n", serde_json::json!({"format": FORMAT, "version": 1, "bucket": name, "config": config})).as_bytes()).await?;
+            let mut count = 0u64;
+            for (stored, hash, _) in entries {
+                let path = match &hash {
+                    Some(hash) => cas::blob_path(&bucket_dir, hash),
+                    None => {
+                        // Cold files are brought back first so the archive holds their real content.
+                        if let Err(e) = cold::rehydrate(&state, &name, &stored, true).await { tracing::warn!(bucket = %name, %stored, error = %e, "rehydration for export failed"); continue; }
+                        bucket_dir.join(&stored)
+                    }
+                };
+                let Ok(mut file) = tokio::fs::File::open(path).await else { continue };
+                let m = file.metadata().await?;
+                if !m.is_file() { continue; }
+                // A blob's metadata is kept under its hash.
+                let metadata = load_file_metadata(&bucket_dir, hash.as_deref().unwrap_or(&stored)).unwrap_or_default();
+                let original_name = hash.is_none().then(|| original_key_of(&bucket_dir, &stored));
+                let head = EntryHeader { name: stored.clone(), size: m.len(), hash, original_name, metadata };
+                tx.write_all(format!("{}\n", serde_json::to_string(&head)?).as_bytes()).await?;
+                // Never write more than announced, even if the file grows meanwhile.
+                let copied = tokio::io::copy(&mut (&mut file).take(head.size), &mut tx).await?;
+                if copied != head.size { return Err(anyhow::anyhow!("{} shrank during export", stored)); }
+                count += 1;
+            }
+            tx.write_all(format!("{}\n", serde_json::json!({"end": true, "count": count})).as_bytes()).await?;
+            tx.shutdown().await?;
+            anyhow::Ok(())
+        };
+        // The missing end line tells the importer the archive is incomplete.
+        if let Err(e) = write.await { tracing::error!(error = %e, bucket = %name, "export aborted"); }
+    });
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, CONTENT_TYPE.parse().unwrap());
+    headers.insert(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.export\"", bucket).parse().unwrap());
+    let mut resp = (StatusCode::OK, headers, Body::from_stream(tokio_util::io::ReaderStream::new(rx))).into_response();
+    resp.extensions_mut().insert(PayloadSize(payload));
+    resp
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery { #[serde(default)] pub overwrite: bool }
+
+pub async fn import_bucket(State(state): State<AppState>, AxPath(bucket): AxPath<String>, Query(q): Query<ImportQuery>, body: Body) -> impl IntoResponse {
+    let Some(bucket_dir) = state.resolve_bucket_path(&bucket) else { return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"储存桶名称不合法"}))).into_response() };
+    // A bucket the import creates is held to the same naming rules as `POST /api/buckets`.
+    if !bucket_dir.exists() {
+        if let Err(msg) = validate_bucket_name(&bucket, state.s3_compat_names) { return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error": msg}))).into_response(); }
+    }
+    let _writing = BucketWriteGuard::enter(&state, &bucket);
+    let created = fs::create_dir_all(&bucket_dir).and_then(|_| if state.content_addressed { fs::create_dir_all(upload_path(&bucket_dir, "")) } else { Ok(()) });
+    if let Err(e) = created { return (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"创建储存桶失败","details":e.to_string()}))).into_response(); }
+    let stream = body.into_data_stream().map_err(std::io::Error::other);
+    let mut reader = tokio::io::BufReader::new(tokio_util::io::StreamReader::new(stream));
+    let bad = |msg: &str| (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error": msg}))).into_response();
+
+    match read_line(&mut reader).await {
+        Ok(Some(v)) if v.get("format").and_then(|f| f.as_str()) == Some(FORMAT) => {
+            // Keep an existing bucket's own settings; only seed them on a fresh bucket.
+            if !has_bucket_config(&bucket_dir) {
+                if let Some(mut config) = v.get("config").cloned().and_then(|c| serde_json::from_value::<BucketConfig>(c).ok()) {
+                    config.quota_bytes = config.quota_bytes.filter(|&q| q > 0);
+                    if !config.default_metadata.is_empty() || config.quota_bytes.is_some() {
+                        if let Err(e) = save_bucket_config(&bucket_dir, &config) { return (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"保存储存桶配置失败","details":e.to_string()}))).into_response(); }
+                    }
+                }
+            }
+        }
+        _ => return bad("导入数据格式不正确"),
+    }
+    // Read after the header so a quota the archive brings applies to its own files.
+    let mut quota = Quota::of(&state, &bucket_dir, &load_bucket_config(&bucket_dir));
+    let mut imported = Vec::new();
+    let mut skipped = Vec::new();
+    loop {
+        let line = match read_line(&mut reader).await {
+            Ok(Some(v)) => v,
+            Ok(None) => return bad("导入数据不完整"),
+            Err(e) => return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"导入数据读取失败","details":e.to_string()}))).into_response(),
+        };
+        if line.get("end").and_then(|v| v.as_bool()) == Some(true) { break; }
+        let Ok(head) = serde_json::from_value::<EntryHeader>(line) else { return bad("导入数据格式不正确") };
+        // Content-addressed names go back into the content store when it is enabled;
+        // otherwise they become plain files under their name.
+        let hash = head.hash.clone().filter(|_| state.content_addressed);
+        let (target, valid, exists) = match &hash {
+            Some(h) => (upload_path(&bucket_dir, &format!("import-{}-{}", chrono::Utc::now().timestamp_millis(), rand_u32())),
+                is_content_hash(h) && is_plain_name(&head.name) && !is_hidden(&head.name), cas::hash_of(&bucket_dir, &head.name).is_some()),
+            None => (bucket_dir.join(&head.name), is_safe_key(&head.name) && !is_hidden(split_key(&head.name).1), bucket_dir.join(&head.name).exists()),
+        };
+        let mut blob = (&mut reader).take(head.size);
+        if !valid || (exists && !q.overwrite) {
+            // Still consume the blob so the next header lines up.
+            if let Err(e) = tokio::io::copy(&mut blob, &mut tokio::io::sink()).await { return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"导入数据读取失败","details":e.to_string()}))).into_response(); }
+            skipped.push(head.name);
+            continue;
+        }
+        // Each file is held to the same limit as an upload; its bytes haven't been read yet.
+        if head.size > state.max_upload_bytes { return payload_too_large(&state); }
+        if let Some(quota) = quota.as_mut() {
+            // Content the bucket already holds adds nothing to it.
+            let adds = if hash.as_deref().is_some_and(|h| cas::blob_path(&bucket_dir, h).is_file()) { 0 } else { head.size };
+            // Files before this one stay imported; say which, so the rest can be retried.
+            if adds > quota.left { return quota.exceeded_with(serde_json::json!({"filename": head.name, "imported": imported, "skipped": skipped})); }
+            quota.left -= adds;
+        }
+        let written = async {
+            if let Some(parent) = target.parent() { tokio::fs::create_dir_all(parent).await?; }
+            let mut out = tokio::fs::File::create(&target).await?;
+            let n = tokio::io::copy(&mut blob, &mut out).await?;
+            out.flush().await?;
+            if n != head.size { return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "导入数据不完整")); }
+            if let Some(h) = &hash {
+                if sha256_file(&target).await? != *h { return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "内容与哈希不符")); }
+            }
+            Ok(())
+        }.await;
+        if let Err(e) = written {
+            let _ = tokio::fs::remove_file(&target).await;
+            return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"导入失败","filename": head.name,"details":e.to_string(),"imported": imported}))).into_response();
+        }
+        match &hash {
+            Some(h) => {
+                let linked = async { commit_blob(&bucket_dir, &target, h).await?; link_name(&bucket_dir, &head.name, h).await }.await;
+                if let Err(e) = linked {
+                    let _ = tokio::fs::remove_file(&target).await;
+                    return (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"导入失败","filename": head.name,"details":e.to_string(),"imported": imported}))).into_response();
+                }
+                if !head.metadata.is_empty() { let _ = save_file_metadata(&bucket_dir, h, &head.metadata).await; }
+                register_location(&state, &bucket, h, &head.name, Some((head.size, h))).await;
+            }
+            None => {
+                if !head.metadata.is_empty() { let _ = save_file_metadata(&bucket_dir, &head.name, &head.metadata).await; }
+                let recorded = head.original_name.as_deref().map(|o| split_key(o).1).filter(|n| is_plain_name(n));
+                let original = recorded.map_or_else(|| original_key(&head.name), |n| join_key(split_key(&head.name).0, n));
+                let _ = save_original_name(&bucket_dir, &head.name, &original).await;
+                register_location(&state, &bucket, &head.name, &original, None).await;
+            }
+        }
+        imported.push(head.name);
+    }
+    axum::Json(serde_json::json!({"success": true, "bucket": bucket, "imported": imported, "skipped": skipped})).into_response()
+}
+
+/// Reads one bounded header line; `Ok(None)` at end of stream.
+async fn read_line<R: tokio::io::AsyncBufRead + Unpin>(reader: &mut R) -> std::io::Result<Option<serde_json::Value>> {
+    let mut buf = Vec::new();
+    let n = reader.take(MAX_LINE).read_until(b'\n', &mut buf).await?;
+    if n == 0 { return Ok(None); }
+    if buf.last() != Some(&b'\n') { return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "header line too long or truncated")); }
+    serde_json::from_slice(&buf).map(Some).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+
+    use super::FORMAT;
+    use crate::meta::load_bucket_config;
+    use crate::testing::{TempRoot, send, test_state};
+
+    /// An archive holding `files`, with `config` as the bucket's settings.
+    fn archive(config: serde_json::Value, files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut out = format!("{}\n", serde_json::json!({"format": FORMAT, "version": 1, "bucket": "src", "config": config})).into_bytes();
+        for (name, data) in files {
+            out.extend(format!("{}\n", serde_json::json!({"name": name, "size": data.len()})).into_bytes());
+            out.extend_from_slice(data);
+        }
+        out.extend(format!("{}\n", serde_json::json!({"end": true, "count": files.len()})).into_bytes());
+        out
+    }
+
+    fn import(bucket: &str, body: Vec<u8>) -> Request<Body> {
+        Request::post(format!("/api/buckets/{}/import", bucket)).body(Body::from(body)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn import_seeds_the_whole_config() {
+        let root = TempRoot::new();
+        let state = test_state(root.path());
+        let resp = send(&state, import("docs", archive(serde_json::json!({"quotaBytes": 100}), &[]))).await;
+        assert_eq!(resp.status, StatusCode::OK);
+        assert_eq!(load_bucket_config(&root.path().join("docs")).quota_bytes, Some(100));
+    }
+
+    #[tokio::test]
+    async fn import_over_quota_reports_what_was_imported() {
+        let root = TempRoot::new();
+        let state = test_state(root.path());
+        let body = archive(serde_json::json!({"quotaBytes": 6}), &[("a.txt", b"aaaa"), ("b.txt", b"bbbb")]);
+        let resp = send(&state, import("docs", body)).await;
+        assert_eq!(resp.status, StatusCode::PAYLOAD_TOO_LARGE);
+        let body = resp.json();
+        assert_eq!((&body["filename"], &body["imported"], &body["skipped"]), (&serde_json::json!("b.txt"), &serde_json::json!(["a.txt"]), &serde_json::json!([])));
+        assert!(root.path().join("docs/a.txt").is_file());
+        assert!(!root.path().join("docs/b.txt").exists());
+    }
+}
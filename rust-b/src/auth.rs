@@ -1,20 +1,115 @@
 use axum::{body::Body, http::StatusCode, response::IntoResponse};
 use axum::extract::State;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
 use crate::state::AppState;
 
+/// One entry of `API_KEYS`.
+pub struct ApiKey {
+    pub label: String,
+    pub key: String,
+    /// Requests per second allowed for this key; `None` means unlimited.
+    pub rps: Option<f64>,
+}
+
+/// The key a request authenticated with, stored in request extensions by `auth_middleware`.
+#[derive(Clone)]
+pub struct AuthedKey {
+    pub label: String,
+    pub rps: Option<f64>,
+    /// Hex SHA-256 of the presented key; `RATE_LIMIT_RPM` is counted per key under it.
+    pub key_hash: String,
+}
+
+/// Parses `API_KEYS` (`label:key[:rps],...`).
+pub fn parse_api_keys(raw: &str) -> anyhow::Result<Vec<ApiKey>> {
+    let mut keys: Vec<ApiKey> = Vec::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let mut parts = entry.splitn(3, ':');
+        let (Some(label), Some(key)) = (parts.next().map(str::trim), parts.next().map(str::trim)) else { anyhow::bail!("API_KEYS 条目格式应为 label:key[:rps]: {}", entry) };
+        if label.is_empty() || key.is_empty() { anyhow::bail!("API_KEYS 条目格式应为 label:key[:rps]: {}", entry); }
+        let rps = match parts.next().map(str::trim) {
+            None | Some("") => None,
+            Some(v) => match v.parse::<f64>() { Ok(r) if r > 0.0 && r.is_finite() => Some(r), _ => anyhow::bail!("API_KEYS 中 {} 的速率限制不合法: {}", label, v) },
+        };
+        if keys.iter().any(|k| k.label == label || k.key == key) { anyhow::bail!("API_KEYS 中的标签或密钥重复: {}", label); }
+        keys.push(ApiKey { label: label.to_string(), key: key.to_string(), rps });
+    }
+    Ok(keys)
+}
+
 pub async fn auth_middleware(
     State(state): State<AppState>,
-    req: axum::http::Request<Body>,
+    mut req: axum::http::Request<Body>,
     next: axum::middleware::Next,
 ) -> axum::response::Response {
-    if let Some(expected) = &state.api_key {
-        if !expected.is_empty() {
-            let headers = req.headers();
-            match headers.get("x-api-key").and_then(|v| v.to_str().ok()) {
-                Some(got) if got == expected => {}
-                _ => return (StatusCode::FORBIDDEN, axum::Json(serde_json::json!({"error":"无效的API密钥"}))).into_response(),
-            }
-        }
+    match authenticate(&state, req.headers()) {
+        Ok(Some(authed)) => { req.extensions_mut().insert(authed); }
+        Ok(None) => {}
+        Err((status, error)) => return (status, axum::Json(serde_json::json!({"error": error}))).into_response(),
     }
     next.run(req).await
-}
\ No newline at end of file
+}
+
+/// The key `headers` carry: `Ok(None)` when no keys are configured, `Err` with the status
+/// and message to send when the key is missing, malformed or unknown.
+pub fn authenticate(state: &AppState, headers: &axum::http::HeaderMap) -> Result<Option<AuthedKey>, (StatusCode, &'static str)> {
+    let legacy = state.api_key.as_deref().filter(|k| !k.is_empty());
+    if legacy.is_none() && state.api_keys.is_empty() { return Ok(None); }
+    let mut values = headers.get_all("x-api-key").iter();
+    let (first, second) = (values.next(), values.next());
+    if second.is_some() { return Err((StatusCode::BAD_REQUEST, "请求中包含多个 x-api-key 头")); }
+    let got = match first.map(|v| std::str::from_utf8(v.as_bytes())) {
+        Some(Ok(got)) => got,
+        Some(Err(_)) => return Err((StatusCode::BAD_REQUEST, "x-api-key 不是合法的 UTF-8")),
+        None => return Err((StatusCode::FORBIDDEN, "无效的API密钥")),
+    };
+    let digest = Sha256::digest(got.as_bytes());
+    let key_hash = hex::encode(digest);
+    // Every configured key is compared, so timing doesn't reveal which one (if any) matched.
+    let mut authed = legacy.filter(|k| keys_match(&digest, k)).map(|_| AuthedKey { label: "default".to_string(), rps: None, key_hash: key_hash.clone() });
+    for k in state.api_keys.iter() {
+        if keys_match(&digest, &k.key) && authed.is_none() { authed = Some(AuthedKey { label: k.label.clone(), rps: k.rps, key_hash: key_hash.clone() }); }
+    }
+    authed.map(Some).ok_or((StatusCode::FORBIDDEN, "无效的API密钥"))
+}
+
+/// Constant-time check of a presented key (as its SHA-256) against a configured one.
+/// Hashing first keeps the comparison time independent of both keys' lengths.
+fn keys_match(got_digest: &[u8], expected: &str) -> bool {
+    got_digest.ct_eq(Sha256::digest(expected.as_bytes()).as_slice()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use sha2::{Digest, Sha256};
+
+    use super::keys_match;
+    use crate::testing::{TempRoot, send, test_state};
+
+    #[test]
+    fn only_the_same_key_matches() {
+        let digest = Sha256::digest(b"s3cret-key");
+        assert!(keys_match(&digest, "s3cret-key"));
+        for other in ["s3cret-kez", "s3cret-ke", "s3cret-key2", "S3CRET-KEY", ""] {
+            assert!(!keys_match(&digest, other), "{:?}", other);
+        }
+    }
+
+    #[tokio::test]
+    async fn wrong_or_missing_keys_are_forbidden() {
+        let root = TempRoot::new();
+        let mut state = test_state(root.path());
+        state.api_key = Some("s3cret-key".to_string());
+        let with_key = |key: Option<&str>| {
+            let mut req = Request::get("/api/buckets");
+            if let Some(key) = key { req = req.header("x-api-key", key); }
+            req.body(Body::empty()).unwrap()
+        };
+        assert_eq!(send(&state, with_key(Some("s3cret-key"))).await.status, StatusCode::OK);
+        assert_eq!(send(&state, with_key(Some("s3cret-kez"))).await.status, StatusCode::FORBIDDEN);
+        assert_eq!(send(&state, with_key(None)).await.status, StatusCode::FORBIDDEN);
+    }
+}
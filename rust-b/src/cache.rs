@@ -0,0 +1,49 @@
+use std::future::Future;
+
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+
+use crate::state::AppState;
+
+/// Generation key used for the bucket list itself.
+const ALL_BUCKETS: &str = "*";
+
+/// Marks a bucket (and the bucket list) as changed so cached ETags stop matching.
+/// Only changes made through this process are seen; edits on disk or on other nodes are not.
+pub fn bump_generation(state: &AppState, bucket: &str) {
+    *state.generations.entry(bucket.to_string()).or_insert(0) += 1;
+    *state.generations.entry(ALL_BUCKETS.to_string()).or_insert(0) += 1;
+}
+
+/// ETag for a bucket's listing/info, or for the bucket list when `bucket` is `None`.
+pub fn generation_etag(state: &AppState, bucket: Option<&str>) -> String {
+    let key = bucket.unwrap_or(ALL_BUCKETS);
+    let gen = state.generations.get(key).map(|g| *g).unwrap_or(0);
+    format!("\"{}-{}\"", state.boot_id, gen)
+}
+
+pub fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers.get_all(header::IF_NONE_MATCH).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().trim_start_matches("W/"))
+        .any(|v| v == "*" || v == etag)
+}
+
+/// Answers with `304` when the client already holds `etag`, otherwise renders the response.
+/// Either way the listing cache headers are attached.
+/// `render` is only awaited on a miss, so the directory walk is skipped on revalidation.
+/// A rendered response that sets its own `Cache-Control` (e.g. a partial listing) keeps it and gets no ETag.
+pub async fn cached<R: IntoResponse>(state: &AppState, headers: &HeaderMap, etag: String, render: impl Future<Output = R>) -> Response {
+    let mut resp = if etag_matches(headers, &etag) { StatusCode::NOT_MODIFIED.into_response() } else { render.await.into_response() };
+    if resp.headers().contains_key(header::CACHE_CONTROL) { return resp; }
+    if resp.status().is_success() || resp.status() == StatusCode::NOT_MODIFIED {
+        if let Ok(v) = HeaderValue::from_str(&etag) { resp.headers_mut().insert(header::ETAG, v); }
+    }
+    with_cache_control(state, resp)
+}
+
+pub fn with_cache_control(state: &AppState, mut resp: Response) -> Response {
+    if let Ok(v) = HeaderValue::from_str(&state.cache_control) { resp.headers_mut().insert(header::CACHE_CONTROL, v); }
+    resp
+}
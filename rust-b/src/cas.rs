@@ -0,0 +1,82 @@
+//! `CONTENT_ADDRESSED=true`: uploads are stored once per bucket under their SHA-256 at
+//! `.cas/ab/cd/<hash>`, and each upload's name points at its hash. Identical content
+//! uploaded under several names shares one blob.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const CAS_DIR: &str = ".cas";
+/// Local copy of the name → hash mapping, one file per name; Redis `names:<bucket>` is
+/// consulted first.
+const NAMES_DIR: &str = "names";
+const UPLOADS_DIR: &str = "uploads";
+
+/// 64 lowercase hex digits, the form hashes are stored and served under.
+pub fn is_content_hash(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// `.cas/ab/cd/abcd…` inside the bucket; the two fan-out levels keep directories small.
+pub fn blob_path(bucket_dir: &Path, hash: &str) -> PathBuf {
+    bucket_dir.join(CAS_DIR).join(&hash[..2]).join(&hash[2..4]).join(hash)
+}
+
+/// Where an upload is written until its hash is known; on the blobs' filesystem, so
+/// committing it is a rename.
+pub fn upload_path(bucket_dir: &Path, unique: &str) -> PathBuf { bucket_dir.join(CAS_DIR).join(UPLOADS_DIR).join(unique) }
+
+fn name_path(bucket_dir: &Path, name: &str) -> PathBuf { bucket_dir.join(CAS_DIR).join(NAMES_DIR).join(name) }
+
+/// Moves a fully written upload into place as the blob for `hash`. Returns `true` when the
+/// bucket already held that content, in which case the upload is discarded.
+pub async fn commit_blob(bucket_dir: &Path, upload: &Path, hash: &str) -> std::io::Result<bool> {
+    let blob = blob_path(bucket_dir, hash);
+    if blob.is_file() {
+        tokio::fs::remove_file(upload).await?;
+        return Ok(true);
+    }
+    if let Some(parent) = blob.parent() { tokio::fs::create_dir_all(parent).await?; }
+    // A concurrent upload of the same bytes may win the rename; its content is identical.
+    tokio::fs::rename(upload, &blob).await?;
+    Ok(false)
+}
+
+pub async fn link_name(bucket_dir: &Path, name: &str, hash: &str) -> std::io::Result<()> {
+    let path = name_path(bucket_dir, name);
+    if let Some(parent) = path.parent() { tokio::fs::create_dir_all(parent).await?; }
+    tokio::fs::write(path, hash).await
+}
+
+pub fn unlink_name(bucket_dir: &Path, name: &str) {
+    let _ = fs::remove_file(name_path(bucket_dir, name));
+}
+
+/// The hash `name` points at in the local mapping.
+pub fn hash_of(bucket_dir: &Path, name: &str) -> Option<String> {
+    let raw = fs::read_to_string(name_path(bucket_dir, name)).ok()?;
+    Some(raw.trim().to_string()).filter(|h| is_content_hash(h))
+}
+
+/// Every `(name, hash)` in the local mapping whose blob is still present.
+pub fn names(bucket_dir: &Path) -> Vec<(String, String)> {
+    let Ok(iter) = fs::read_dir(bucket_dir.join(CAS_DIR).join(NAMES_DIR)) else { return Vec::new() };
+    iter.filter_map(Result::ok)
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .filter_map(|name| { let hash = hash_of(bucket_dir, &name)?; blob_path(bucket_dir, &hash).is_file().then_some((name, hash)) })
+        .collect()
+}
+
+/// `(bytes, names)` held in the content store; shared blobs are counted once.
+pub fn usage(bucket_dir: &Path) -> (u64, usize) {
+    let names = names(bucket_dir);
+    let mut hashes: Vec<&str> = names.iter().map(|(_, h)| h.as_str()).collect();
+    hashes.sort_unstable();
+    hashes.dedup();
+    let bytes = hashes.iter().filter_map(|h| fs::metadata(blob_path(bucket_dir, h)).ok()).map(|m| m.len()).sum();
+    (bytes, names.len())
+}
+
+/// Names still pointing at `hash`; a blob is only removed once this is empty.
+pub fn names_for(bucket_dir: &Path, hash: &str) -> Vec<String> {
+    names(bucket_dir).into_iter().filter(|(_, h)| h == hash).map(|(n, _)| n).collect()
+}
@@ -0,0 +1,66 @@
+use std::path::{Path, PathBuf};
+use axum::{extract::{Path as AxPath, State}, http::StatusCode, response::IntoResponse};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use crate::state::AppState;
+
+/// Where the digest lives when Redis is disabled; hidden, so listings skip it.
+fn sidecar_path(bucket_dir: &Path, stored: &str) -> PathBuf { crate::util::sidecar_path(bucket_dir, stored, ".sha256") }
+
+pub async fn save_sidecar(bucket_dir: &Path, stored: &str, sha256: &str) {
+    let _ = tokio::fs::write(sidecar_path(bucket_dir, stored), sha256).await;
+}
+
+pub fn remove_sidecar(bucket_dir: &Path, stored: &str) {
+    let _ = std::fs::remove_file(sidecar_path(bucket_dir, stored));
+}
+
+/// The hex SHA-256 recorded at upload time: the `sha256` field of the file's location
+/// entry, else the sidecar written when the index was disabled or unreachable.
+pub async fn load_checksum(state: &AppState, bucket: &str, stored: &str) -> Option<String> {
+    if let Some(index) = &state.index {
+        if let Ok(Some(loc)) = index.get_location(bucket, stored).await {
+            let sha = serde_json::from_str::<serde_json::Value>(&loc).ok().and_then(|v| v.get("sha256")?.as_str().map(str::to_string));
+            if sha.is_some() { return sha; }
+        }
+    }
+    let raw = tokio::fs::read_to_string(sidecar_path(&state.resolve_bucket_path(bucket)?, stored)).await.ok()?;
+    Some(raw.trim().to_string()).filter(|s| !s.is_empty())
+}
+
+/// Drops a recorded digest once the file's bytes change (appends, rotation).
+pub async fn clear_checksum(state: &AppState, bucket: &str, stored: &str) {
+    if let Some(dir) = state.resolve_bucket_path(bucket) { remove_sidecar(&dir, stored); }
+    let Some(index) = &state.index else { return };
+    let Ok(Some(loc)) = index.get_location(bucket, stored).await else { return };
+    let Ok(mut obj) = serde_json::from_str::<serde_json::Value>(&loc) else { return };
+    let Some(map) = obj.as_object_mut() else { return };
+    if map.remove("sha256").is_none() { return; }
+    map.remove("size");
+    let _ = index.set_location(bucket, stored, &obj.to_string()).await;
+}
+
+pub async fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 { break; }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Re-reads a stored file and compares its digest with the one recorded at upload.
+pub async fn verify_file(State(state): State<AppState>, AxPath((bucket, filename)): AxPath<(String, String)>) -> impl IntoResponse {
+    if state.resolve_file_path(&bucket, &filename).is_none() { return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"文件名不合法"}))).into_response(); }
+    let Some(stored) = crate::handlers::resolve_stored_name(&state, &bucket, &filename).await else { return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"文件不存在"}))).into_response() };
+    let Some(path) = state.resolve_file_path(&bucket, &stored).filter(|p| p.is_file()) else { return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"文件不存在"}))).into_response() };
+    let Some(expected) = load_checksum(&state, &bucket, &stored).await else { return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"该文件没有记录校验和"}))).into_response() };
+    match sha256_file(&path).await {
+        Ok(actual) => axum::Json(serde_json::json!({"valid": actual.eq_ignore_ascii_case(&expected), "checksum": expected})).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"读取文件失败","details":e.to_string()}))).into_response(),
+    }
+}
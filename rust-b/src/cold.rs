@@ -0,0 +1,283 @@
+use std::fs;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+
+use crate::amplify::{AmplificationCounters, bounded_gunzip};
+use crate::redis::{hdel, hset};
+use crate::state::AppState;
+use crate::util::{is_hidden, join_key, sidecar_path};
+
+/// `COLD_DIR` settings; present only when the cold tier is enabled.
+#[derive(Clone)]
+pub struct ColdTier {
+    pub dir: PathBuf,
+    /// Files not read or written for this long are demoted (`COLD_AFTER_DAYS`).
+    pub after: Duration,
+    /// Cold files up to this size are restored within the download request; larger ones
+    /// are restored in the background while the client gets `202` (`COLD_INLINE_BYTES`).
+    pub inline_bytes: u64,
+}
+
+const SCAN_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Left in the bucket directory as `.<stored>.cold` so the file stays listed and resolvable.
+#[derive(Serialize, Deserialize)]
+pub struct ColdMarker {
+    /// Size of the original, uncompressed file.
+    pub size: u64,
+    /// Unix seconds; restored onto the file when it is rehydrated.
+    pub modified: u64,
+    pub path: PathBuf,
+}
+
+fn marker_path(bucket_dir: &Path, stored: &str) -> PathBuf { sidecar_path(bucket_dir, stored, ".cold") }
+
+/// The stored name a `.<stored>.cold` directory entry stands for.
+pub fn stored_from_marker(name: &str) -> Option<&str> {
+    name.strip_prefix('.')?.strip_suffix(".cold").filter(|s| !s.is_empty())
+}
+
+pub fn load_marker(bucket_dir: &Path, stored: &str) -> Option<ColdMarker> {
+    fs::read(marker_path(bucket_dir, stored)).ok().and_then(|b| serde_json::from_slice(&b).ok())
+}
+
+/// Whether `stored` exists in the bucket, either on disk or demoted to the cold tier.
+pub fn is_present(bucket_dir: &Path, stored: &str) -> bool {
+    bucket_dir.join(stored).is_file() || marker_path(bucket_dir, stored).is_file()
+}
+
+/// A file a bucket holds, as found by `walk_bucket`.
+pub enum BucketEntry {
+    Plain { key: String, meta: fs::Metadata },
+    /// Demoted to the cold tier; `size` is the original's.
+    Cold { key: String, size: u64 },
+}
+
+impl BucketEntry {
+    pub fn key(&self) -> &str {
+        match self { BucketEntry::Plain { key, .. } | BucketEntry::Cold { key, .. } => key }
+    }
+
+    /// Bytes on disk, or for a cold file the bytes it will take once restored.
+    pub fn size(&self) -> u64 {
+        match self { BucketEntry::Plain { meta, .. } => meta.len(), BucketEntry::Cold { size, .. } => *size }
+    }
+}
+
+/// Visits every file in `bucket_dir` and its visible folders, cold ones included. Other
+/// hidden entries (sidecars, `.cas`) are skipped and symlinked folders aren't followed.
+/// Returns `false` if `visit` stopped the walk by returning `false`.
+pub fn walk_bucket(bucket_dir: &Path, mut visit: impl FnMut(BucketEntry) -> bool) -> bool {
+    let mut pending = vec![(bucket_dir.to_path_buf(), String::new())];
+    while let Some((dir, folder)) = pending.pop() {
+        let Ok(iter) = fs::read_dir(&dir) else { continue };
+        for entry in iter.filter_map(Result::ok) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let found = if let Some(stored) = stored_from_marker(&name) {
+                let Some(marker) = load_marker(&dir, stored) else { continue };
+                BucketEntry::Cold { key: join_key(&folder, stored), size: marker.size }
+            } else {
+                if is_hidden(&name) { continue; }
+                if entry.file_type().is_ok_and(|t| t.is_dir()) { pending.push((entry.path(), join_key(&folder, &name))); continue; }
+                match fs::metadata(entry.path()) {
+                    Ok(meta) if meta.is_file() => BucketEntry::Plain { key: join_key(&folder, &name), meta },
+                    _ => continue,
+                }
+            };
+            if !visit(found) { return false; }
+        }
+    }
+    true
+}
+
+pub enum Rehydration {
+    /// The file is on disk (or was never cold).
+    Ready,
+    /// A background restore is running; retry shortly.
+    Pending,
+}
+
+/// Brings a demoted file back into its bucket. With `wait` the restore always completes
+/// before returning; otherwise files over `inline_bytes` are restored in the background.
+pub async fn rehydrate(state: &AppState, bucket: &str, stored: &str, wait: bool) -> std::io::Result<Rehydration> {
+    let Some(tier) = &state.cold else { return Ok(Rehydration::Ready) };
+    let Some(bucket_dir) = state.resolve_bucket_path(bucket) else { return Ok(Rehydration::Ready) };
+    if bucket_dir.join(stored).is_file() { return Ok(Rehydration::Ready); }
+    let Some(marker) = load_marker(&bucket_dir, stored) else { return Ok(Rehydration::Ready) };
+    if wait || marker.size <= tier.inline_bytes {
+        restore(state, bucket, &bucket_dir, stored).await?;
+        return Ok(Rehydration::Ready);
+    }
+    let target = bucket_dir.join(stored);
+    if state.rehydrating.insert(target.clone(), ()).is_none() {
+        let (state, bucket, stored) = (state.clone(), bucket.to_string(), stored.to_string());
+        tokio::spawn(async move {
+            if let Err(e) = restore(&state, &bucket, &bucket_dir, &stored).await { tracing::warn!(%bucket, %stored, error = %e, "rehydration failed"); }
+            state.rehydrating.remove(&target);
+        });
+    }
+    Ok(Rehydration::Pending)
+}
+
+async fn restore(state: &AppState, bucket: &str, bucket_dir: &Path, stored: &str) -> std::io::Result<()> {
+    let target = bucket_dir.join(stored);
+    let lock = state.file_locks.entry(target.clone()).or_default().clone();
+    let guard = lock.lock().await;
+    let (dir, name, counters) = (bucket_dir.to_path_buf(), stored.to_string(), state.amplification.clone());
+    let result = tokio::task::spawn_blocking(move || restore_blocking(&dir, &name, &counters)).await.map_err(std::io::Error::other).and_then(|r| r);
+    drop(guard);
+    drop(lock);
+    state.file_locks.remove_if(&target, |_, l| std::sync::Arc::strong_count(l) == 1);
+    if result.is_ok() {
+        if let Some(redis) = &state.redis { let _ = hdel(redis, &cold_key(bucket), stored).await; }
+    }
+    result
+}
+
+fn restore_blocking(bucket_dir: &Path, stored: &str, counters: &AmplificationCounters) -> std::io::Result<()> {
+    let target = bucket_dir.join(stored);
+    // Another request may have restored it while we waited for the lock.
+    if target.is_file() { return Ok(()); }
+    let Some(marker) = load_marker(bucket_dir, stored) else { return Ok(()) };
+    let tmp = sidecar_path(bucket_dir, stored, ".rehydrate.tmp");
+    let result = (|| {
+        let input = fs::File::open(&marker.path)?;
+        let compressed_len = input.metadata()?.len();
+        let mut out = fs::File::create(&tmp)?;
+        // The cold copy must expand to exactly the size recorded at demotion.
+        bounded_gunzip(BufReader::new(input), compressed_len, marker.size, &mut out, counters)?;
+        out.set_modified(UNIX_EPOCH + Duration::from_secs(marker.modified))?;
+        out.sync_all()?;
+        fs::rename(&tmp, &target)
+    })();
+    if result.is_err() { let _ = fs::remove_file(&tmp); return result; }
+    let _ = fs::remove_file(marker_path(bucket_dir, stored));
+    let _ = fs::remove_file(&marker.path);
+    Ok(())
+}
+
+/// Deletes a demoted file's cold copy and marker; `false` when `stored` isn't cold.
+pub async fn discard(state: &AppState, bucket: &str, bucket_dir: &Path, stored: &str) -> bool {
+    let Some(marker) = load_marker(bucket_dir, stored) else { return false };
+    let _ = tokio::fs::remove_file(&marker.path).await;
+    let _ = tokio::fs::remove_file(marker_path(bucket_dir, stored)).await;
+    if let Some(redis) = &state.redis { let _ = hdel(redis, &cold_key(bucket), stored).await; }
+    true
+}
+
+fn cold_key(bucket: &str) -> String { format!("cold:{}", bucket) }
+
+/// Periodically demotes files that haven't been read or written for `COLD_AFTER_DAYS`.
+/// Access times follow the mount's `atime` policy (`relatime` updates them at most daily).
+pub async fn cold_tier_task(state: AppState) {
+    let Some(tier) = state.cold.clone() else { return };
+    let mut ticker = tokio::time::interval(SCAN_INTERVAL);
+    loop {
+        ticker.tick().await;
+        for (bucket, bucket_dir) in buckets(&state) {
+            let mut due = Vec::new();
+            walk_bucket(&bucket_dir, |entry| {
+                if let BucketEntry::Plain { key, meta } = entry {
+                    let last_touched = meta.accessed().ok().max(meta.modified().ok()).unwrap_or(UNIX_EPOCH);
+                    if SystemTime::now().duration_since(last_touched).unwrap_or_default() >= tier.after { due.push(key); }
+                }
+                true
+            });
+            for stored in due {
+                if let Err(e) = demote(&state, &tier, &bucket, &bucket_dir, &stored).await { tracing::warn!(%bucket, %stored, error = %e, "demotion failed"); }
+            }
+        }
+    }
+}
+
+fn buckets(state: &AppState) -> Vec<(String, PathBuf)> {
+    let mut out: Vec<(String, PathBuf)> = state.bucket_paths.iter().map(|(b, p)| (b.clone(), p.clone())).collect();
+    if let Ok(iter) = fs::read_dir(&state.root_dir) {
+        for entry in iter.filter_map(Result::ok) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !is_hidden(&name) && entry.path().is_dir() && !state.bucket_paths.contains_key(&name) { out.push((name, entry.path())); }
+        }
+    }
+    out
+}
+
+async fn demote(state: &AppState, tier: &ColdTier, bucket: &str, bucket_dir: &Path, stored: &str) -> std::io::Result<()> {
+    let path = bucket_dir.join(stored);
+    // Appends and restores of the same file hold this lock too.
+    let lock = state.file_locks.entry(path.clone()).or_default().clone();
+    let guard = lock.lock().await;
+    let (cold_dir, dir, name) = (tier.dir.join(bucket), bucket_dir.to_path_buf(), stored.to_string());
+    let result = tokio::task::spawn_blocking(move || demote_blocking(&cold_dir, &dir, &name)).await.map_err(std::io::Error::other).and_then(|r| r);
+    drop(guard);
+    drop(lock);
+    state.file_locks.remove_if(&path, |_, l| std::sync::Arc::strong_count(l) == 1);
+    if let (Ok(Some(marker)), Some(redis)) = (&result, &state.redis) {
+        let value = serde_json::json!({"node": state.node_id, "path": marker.path, "size": marker.size}).to_string();
+        let _ = hset(redis, &cold_key(bucket), stored, &value).await;
+    }
+    result.map(|_| ())
+}
+
+fn demote_blocking(cold_dir: &Path, bucket_dir: &Path, stored: &str) -> std::io::Result<Option<ColdMarker>> {
+    let path = bucket_dir.join(stored);
+    let Ok(m) = fs::metadata(&path) else { return Ok(None) };
+    let cold_path = cold_dir.join(format!("{}.gz", stored));
+    if let Some(parent) = cold_path.parent() { fs::create_dir_all(parent)?; }
+    let tmp = sidecar_path(cold_dir, stored, ".gz.tmp");
+    let marker = ColdMarker {
+        size: m.len(),
+        modified: m.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs()),
+        path: cold_path.clone(),
+    };
+    let result = (|| {
+        let mut encoder = flate2::write::GzEncoder::new(BufWriter::new(fs::File::create(&tmp)?), flate2::Compression::default());
+        std::io::copy(&mut BufReader::new(fs::File::open(&path)?), &mut encoder)?;
+        encoder.finish()?.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&tmp, &cold_path)?;
+        fs::write(marker_path(bucket_dir, stored), serde_json::to_vec(&marker)?)?;
+        fs::remove_file(&path)
+    })();
+    if let Err(e) = result {
+        // Removing the original is the last step, so it is still intact here.
+        let _ = fs::remove_file(&tmp);
+        let _ = fs::remove_file(&cold_path);
+        let _ = fs::remove_file(marker_path(bucket_dir, stored));
+        return Err(e);
+    }
+    Ok(Some(marker))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::StatusCode;
+
+    use super::{ColdTier, cold_tier_task, load_marker};
+    use crate::testing::{TempRoot, create_bucket, file_part, get, test_state, upload};
+
+    #[tokio::test]
+    async fn sweep_demotes_nested_files() {
+        let root = TempRoot::new();
+        let mut state = test_state(&root.path().join("hot"));
+        state.cold = Some(ColdTier { dir: root.path().join("cold"), after: Duration::ZERO, inline_bytes: 1024 });
+        create_bucket(&state, "docs").await;
+        upload(&state, "/api/buckets/docs/upload?path=2024/06", &[file_part("a.txt", b"nested")]).await;
+        let stored = std::fs::read_dir(root.path().join("hot/docs/2024/06")).unwrap().filter_map(Result::ok)
+            .map(|e| e.file_name().to_string_lossy().to_string()).find(|n| !n.starts_with('.')).unwrap();
+        let key = format!("2024/06/{}", stored);
+        // The first sweep runs as soon as the task starts.
+        let sweep = tokio::spawn(cold_tier_task(state.clone()));
+        for _ in 0..200 {
+            if load_marker(&root.path().join("hot/docs"), &key).is_some() { break; }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        sweep.abort();
+        assert!(load_marker(&root.path().join("hot/docs"), &key).is_some(), "nested file was never demoted");
+        assert!(root.path().join("cold/docs").join(format!("{}.gz", key)).is_file());
+        let resp = get(&state, &format!("/api/buckets/docs/files/{}", key)).await;
+        assert_eq!((resp.status, resp.body.as_slice()), (StatusCode::OK, b"nested".as_slice()));
+    }
+}
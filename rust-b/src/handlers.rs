@@ -1,162 +1,1654 @@
 use std::fs;
-use std::path::PathBuf;
-use axum::{body::Body, extract::{Path as AxPath, State, Multipart}, http::{HeaderMap, StatusCode, header}, response::IntoResponse};
+use std::path::Path;
+use std::time::Duration;
+use axum::{body::Body, extract::{Path as AxPath, Query, State, Multipart}, http::{HeaderMap, StatusCode, header}, response::IntoResponse};
 use serde::{Deserialize, Serialize};
+use futures_util::{StreamExt, TryStreamExt};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
-use crate::state::{AppState, port_from_env};
-use crate::util::{ensure_dir, format_time, rand_u32};
-use crate::redis::{set_key, get_key, del_key, register_node, list_nodes};
+use crate::cold::{self, Rehydration, discard, load_marker, rehydrate, stored_from_marker, walk_bucket};
+use crate::checksum::{clear_checksum, load_checksum, remove_sidecar, save_sidecar, sha256_file};
+use crate::cache::{bump_generation, cached, generation_etag, with_cache_control};
+use crate::cas::{self, commit_blob, is_content_hash, link_name, upload_path};
+use crate::index::NodeStatus;
+use crate::state::{AppState, UploadProgress, port_from_env};
+use crate::meta::{BucketConfig, CONTENT_TYPE_KEY, Metadata, load_bucket_config, load_file_metadata, merge_metadata, metadata_from_headers, original_key_of, remove_file_metadata, remove_original_name, save_bucket_config, save_file_metadata, save_original_name};
+use crate::util::{ByteRange, content_disposition, format_time, html_escape, is_hidden, is_plain_name, is_safe_key, join_key, original_key, parse_byte_range, percent_encode_key, percent_encode_segment, rand_u32, sniff_content_type, split_key, unique_timestamp, validate_bucket_name};
+use crate::prune::BucketWriteGuard;
+use crate::proxy::{PROXIED_HEADER, proxy_download};
+use crate::quarantine::quarantine_upload;
+use crate::receipt::{Receipt, issue_receipt};
+use crate::rotate::rotate_if_needed;
+use crate::trace::TraceContext;
+use crate::telemetry::{record_delete, record_download, record_upload};
+use crate::redis::{RedisPool, set_key, get_key, del_key, hset, hget, hdel, ping, set_nx_ex};
 
 #[derive(Serialize)]
-pub struct BucketInfo { pub name: String, pub size: u64, pub created: String, pub modified: String, pub fileCount: usize }
+pub struct BucketInfo {
+    pub name: String,
+    pub size: u64,
+    pub created: Option<String>,
+    pub modified: Option<String>,
+    #[serde(rename = "fileCount")]
+    pub file_count: usize,
+    #[serde(rename = "quotaBytes")]
+    pub quota_bytes: Option<u64>,
+    #[serde(rename = "usedBytes")]
+    pub used_bytes: u64,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub partial: bool,
+}
 
 #[derive(Serialize)]
-pub struct BucketsResponse { pub buckets: Vec<BucketInfo> }
+pub struct BucketsResponse {
+    pub buckets: Vec<BucketInfo>,
+}
 
 #[derive(Deserialize)]
-pub struct CreateBucketReq { pub name: String }
+pub struct CreateBucketReq {
+    pub name: String,
+    #[serde(rename = "defaultMetadata", default)]
+    pub default_metadata: Metadata,
+    #[serde(rename = "quotaBytes")]
+    pub quota_bytes: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct UploadFilesResp {
+    pub success: bool,
+    pub files: Vec<UploadedFile>,
+}
 
 #[derive(Serialize)]
-pub struct UploadFileResp { pub success: bool, pub file: FileInfo }
+pub struct UploadedFile {
+    #[serde(flatten)]
+    pub file: FileInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<Receipt>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub deduplicated: bool,
+}
 
 #[derive(Serialize)]
-pub struct FileInfo { pub name: String, pub originalName: String, pub size: u64, pub path: String, pub bucket: String }
+pub struct FileInfo {
+    pub name: String,
+    #[serde(rename = "originalName")]
+    pub original_name: String,
+    pub size: u64,
+    pub path: String,
+    pub bucket: String,
+    #[serde(skip_serializing_if = "Metadata::is_empty")]
+    pub metadata: Metadata,
+}
 
 #[derive(Serialize)]
-pub struct FilesListResp { pub files: Vec<FileInfoShort>, pub bucket: String }
+pub struct FilesListResp {
+    pub files: Vec<FileInfoShort>,
+    pub bucket: String,
+    pub total: usize,
+    #[serde(rename = "hasMore")]
+    pub has_more: bool,
+}
 
 #[derive(Serialize)]
-pub struct FileInfoShort { pub name: String, pub size: u64, pub created: String, pub modified: String, pub bucket: String }
-
-pub async fn list_buckets(State(state): State<AppState>) -> impl IntoResponse {
-    let mut buckets = Vec::new();
-    match fs::read_dir(&state.root_dir) {
-        Ok(rd) => {
-            for entry in rd.filter_map(Result::ok) {
-                let bucket_name = entry.file_name().to_string_lossy().to_string();
-                let bucket_path = entry.path();
-                if bucket_path.is_dir() {
-                    let meta = match fs::metadata(&bucket_path) { Ok(m) => m, Err(_) => continue };
-                    let mut size: u64 = 0;
-                    let mut file_count: usize = 0;
-                    if let Ok(files_iter) = fs::read_dir(&bucket_path) {
-                        for f in files_iter.filter_map(Result::ok) {
-                            if let Ok(m) = fs::metadata(f.path()) { if m.is_file() { size += m.len(); file_count += 1; } }
-                        }
+pub struct FileInfoShort {
+    pub name: String,
+    pub size: u64,
+    pub created: Option<String>,
+    pub modified: Option<String>,
+    pub bucket: String,
+}
+
+pub async fn list_buckets(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let etag = generation_etag(&state, None);
+    cached(&state, &headers, etag, async {
+        let mut buckets = Vec::new();
+        let mut any_partial = false;
+        match fs::read_dir(&state.root_dir) {
+            Ok(rd) => {
+                // A `BUCKET_PATHS` mapping shadows any same-named directory under the root.
+                let local = rd.filter_map(Result::ok).map(|e| (e.file_name().to_string_lossy().to_string(), e.path())).filter(|(name, _)| !state.bucket_paths.contains_key(name));
+                let mapped = state.bucket_paths.iter().map(|(name, dir)| (name.clone(), dir.clone()));
+                for (bucket_name, bucket_path) in local.chain(mapped) {
+                    if bucket_path.is_dir() {
+                        let meta = match fs::metadata(&bucket_path) { Ok(m) => m, Err(_) => continue };
+                        let mut size: u64 = 0;
+                        let mut file_count: usize = 0;
+                        // Cold files still count against the quota; they come back on access.
+                        let mut cold_bytes: u64 = 0;
+                        // Each bucket gets its own budget so one huge bucket can't starve the rest.
+                        let deadline = state.listing_walk_timeout.map(|t| std::time::Instant::now() + t);
+                        let complete = walk_bucket(&bucket_path, |entry| {
+                            if deadline.is_some_and(|d| std::time::Instant::now() >= d) { return false; }
+                            match entry {
+                                cold::BucketEntry::Cold { size: bytes, .. } => cold_bytes += bytes,
+                                cold::BucketEntry::Plain { meta, .. } => { size += meta.len(); file_count += 1; }
+                            }
+                            true
+                        });
+                        let partial = !complete;
+                        if state.content_addressed { let (bytes, names) = cas::usage(&bucket_path); size += bytes; file_count += names; }
+                        any_partial |= partial;
+                        let quota_bytes = load_bucket_config(&bucket_path).quota_bytes;
+                        buckets.push(BucketInfo { name: bucket_name, size, created: format_time(meta.created().ok()), modified: format_time(meta.modified().ok()), file_count, quota_bytes, used_bytes: size + cold_bytes, partial });
                     }
-                    buckets.push(BucketInfo { name: bucket_name, size, created: format_time(meta.created().ok()), modified: format_time(meta.modified().ok()), fileCount: file_count });
                 }
+                let resp = axum::Json(BucketsResponse { buckets }).into_response();
+                // Incomplete totals must not be revalidated as if they were the real listing.
+                if any_partial { ([(header::CACHE_CONTROL, "no-store")], resp).into_response() } else { resp }
             }
-            axum::Json(BucketsResponse { buckets }).into_response()
+            Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"无法读取储存桶目录"}))).into_response(),
         }
-        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"无法读取储存桶目录"}))).into_response(),
-    }
+    }).await
 }
 
 pub async fn create_bucket(State(state): State<AppState>, axum::Json(payload): axum::Json<CreateBucketReq>) -> impl IntoResponse {
     let name = payload.name;
-    if name.is_empty() { return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"储存桶名称不能为空"}))).into_response(); }
-    let valid = name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') && !name.starts_with('-') && !name.ends_with('-');
-    if !valid { return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"储存桶名称只能包含小写字母、数字和连字符，且不能以连字符开头或结尾"}))).into_response(); }
-    let bucket_dir = state.root_dir.join(&name);
+    let default_metadata = payload.default_metadata;
+    let quota_bytes = payload.quota_bytes;
+    if quota_bytes == Some(0) { return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"配额必须大于 0"}))).into_response(); }
+    if let Err(msg) = validate_bucket_name(&name, state.s3_compat_names) { return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error": msg}))).into_response(); }
+    let Some(bucket_dir) = state.resolve_bucket_path(&name) else { return invalid_bucket() };
     if bucket_dir.exists() { return (StatusCode::CONFLICT, axum::Json(serde_json::json!({"error":"储存桶已存在"}))).into_response(); }
     if let Err(e) = fs::create_dir_all(&bucket_dir) { return (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"创建储存桶失败","details":e.to_string()}))).into_response(); }
-    axum::Json(serde_json::json!({"success":true, "bucket": {"name": name}})).into_response()
+    if !default_metadata.is_empty() || quota_bytes.is_some() {
+        if let Err(e) = save_bucket_config(&bucket_dir, &BucketConfig { default_metadata: default_metadata.clone(), quota_bytes }) { return (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"创建储存桶失败","details":e.to_string()}))).into_response(); }
+    }
+    bump_generation(&state, &name);
+    axum::Json(serde_json::json!({"success":true, "bucket": {"name": name, "defaultMetadata": default_metadata, "quotaBytes": quota_bytes}})).into_response()
 }
 
 pub async fn delete_bucket(State(state): State<AppState>, AxPath(bucket): AxPath<String>) -> impl IntoResponse {
-    let bucket_dir = state.root_dir.join(&bucket);
+    let Some(bucket_dir) = state.resolve_bucket_path(&bucket) else { return invalid_bucket() };
     if !bucket_dir.exists() { return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"储存桶不存在"}))).into_response(); }
+    // The mapped directory is configured, not created by us; removing it would break the next startup.
+    if state.bucket_paths.contains_key(&bucket) { return (StatusCode::CONFLICT, axum::Json(serde_json::json!({"error":"该储存桶映射到自定义目录，无法删除"}))).into_response(); }
     match fs::remove_dir_all(&bucket_dir) {
-        Ok(_) => axum::Json(serde_json::json!({"success": true, "message": "储存桶已成功删除"})).into_response(),
+        Ok(_) => { bump_generation(&state, &bucket); axum::Json(serde_json::json!({"success": true, "message": "储存桶已成功删除"})).into_response() }
         Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"删除储存桶失败","details":e.to_string()}))).into_response(),
     }
 }
 
-pub async fn list_files(State(state): State<AppState>, AxPath(bucket): AxPath<String>) -> impl IntoResponse {
-    let bucket_dir = state.root_dir.join(&bucket);
+/// Paging parameters are parsed leniently: bad or out-of-range values are clamped, not rejected.
+#[derive(Deserialize)]
+pub struct ListFilesQuery {
+    pub limit: Option<String>,
+    pub offset: Option<String>,
+    pub prefix: Option<String>,
+    #[serde(default)]
+    pub recursive: bool,
+}
+
+const DEFAULT_LIST_LIMIT: usize = 100;
+const MAX_LIST_LIMIT: usize = 1000;
+
+pub async fn list_files(State(state): State<AppState>, AxPath(bucket): AxPath<String>, Query(q): Query<ListFilesQuery>, headers: HeaderMap) -> impl IntoResponse {
+    let Some(bucket_dir) = state.resolve_bucket_path(&bucket) else { return invalid_bucket() };
     if !bucket_dir.exists() { return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"储存桶不存在"}))).into_response(); }
-    let mut files = Vec::new();
-    match fs::read_dir(&bucket_dir) {
-        Ok(iter) => {
-            for entry in iter.filter_map(Result::ok) {
-                let p = entry.path();
-                if let Ok(m) = fs::metadata(&p) { if m.is_file() {
-                    files.push(FileInfoShort { name: entry.file_name().to_string_lossy().to_string(), size: m.len(), created: format_time(m.created().ok()), modified: format_time(m.modified().ok()), bucket: bucket.clone() });
-                }}
+    let etag = generation_etag(&state, Some(&bucket));
+    cached(&state, &headers, etag, async {
+        match collect_files(&state, &bucket, &bucket_dir, q.recursive) {
+            Ok(files) => {
+                let limit = q.limit.as_deref().and_then(|v| v.trim().parse::<i64>().ok()).map_or(DEFAULT_LIST_LIMIT, |l| l.clamp(1, MAX_LIST_LIMIT as i64) as usize);
+                let offset = q.offset.as_deref().and_then(|v| v.trim().parse::<i64>().ok()).map_or(0, |o| o.max(0) as usize);
+                let prefix = q.prefix.as_deref().unwrap_or("");
+                // Clean names are already original keys; stripping them again would eat a leading `2024-06-`.
+                let matching: Vec<FileInfoShort> = files.into_iter().filter(|f| if state.clean_names { f.name.starts_with(prefix) } else { original_key_of(&bucket_dir, &f.name).starts_with(prefix) }).collect();
+                let total = matching.len();
+                let files: Vec<FileInfoShort> = matching.into_iter().skip(offset).take(limit).collect();
+                let has_more = offset.saturating_add(files.len()) < total;
+                axum::Json(FilesListResp { files, bucket, total, has_more }).into_response()
+            }
+            Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"无法读取文件目录"}))).into_response(),
+        }
+    }).await
+}
+
+/// Minimal HTML view of a bucket listing, enabled with `DIRECTORY_INDEX_ENABLED`.
+pub async fn directory_index(State(state): State<AppState>, AxPath(bucket): AxPath<String>, headers: HeaderMap) -> impl IntoResponse {
+    if !state.directory_index { return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"目录索引未启用"}))).into_response(); }
+    let Some(bucket_dir) = state.resolve_bucket_path(&bucket) else { return invalid_bucket() };
+    if !bucket_dir.exists() { return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"储存桶不存在"}))).into_response(); }
+    let etag = generation_etag(&state, Some(&bucket));
+    cached(&state, &headers, etag, async {
+        match collect_files(&state, &bucket, &bucket_dir, false) {
+            Ok(files) => axum::response::Html(render_index(&bucket, &files)).into_response(),
+            Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"无法读取文件目录"}))).into_response(),
+        }
+    }).await
+}
+
+/// Files in a bucket, newest modification first. With `recursive`, files in (non-hidden)
+/// folders are included under their key, e.g. `images/2024/logo.png`.
+fn collect_files(state: &AppState, bucket: &str, bucket_dir: &Path, recursive: bool) -> std::io::Result<Vec<FileInfoShort>> {
+    // (modified, version, info)
+    let mut files: Vec<(std::time::SystemTime, i64, FileInfoShort)> = Vec::new();
+    // (directory, its folder key); only the bucket itself has to be readable.
+    let mut pending = vec![(bucket_dir.to_path_buf(), String::new())];
+    while let Some((dir, folder)) = pending.pop() {
+        let iter = match fs::read_dir(&dir) { Ok(iter) => iter, Err(e) if folder.is_empty() => return Err(e), Err(_) => continue };
+        for entry in iter.filter_map(Result::ok) {
+            let p = entry.path();
+            if let Ok(m) = fs::metadata(&p) {
+                let entry_name = entry.file_name().to_string_lossy().to_string();
+                // Symlinked folders aren't followed, so a link cycle can't trap the walk.
+                if recursive && entry.file_type().is_ok_and(|t| t.is_dir()) && !is_hidden(&entry_name) { pending.push((p, join_key(&folder, &entry_name))); continue; }
+                if !m.is_file() { continue; }
+                // Demoted files are listed from their marker, with the original size and mtime.
+                let (stored, size, mtime) = match stored_from_marker(&entry_name).and_then(|s| Some((s, load_marker(&dir, s)?))) {
+                    Some((stored, marker)) => (stored.to_string(), marker.size, std::time::UNIX_EPOCH + Duration::from_secs(marker.modified)),
+                    None if is_hidden(&entry_name) => continue,
+                    None => (entry_name, m.len(), m.modified().unwrap_or(std::time::UNIX_EPOCH)),
+                };
+                let name = join_key(&folder, &if state.clean_names { original_key_of(&dir, &stored) } else { stored.clone() });
+                let info = FileInfoShort { name, size, created: format_time(m.created().ok()), modified: format_time(Some(mtime)), bucket: bucket.to_string() };
+                let version = unique_timestamp(&stored).unwrap_or(0);
+                if state.clean_names {
+                    // Several stored versions can share one clean name; only the newest one is addressable.
+                    if let Some(existing) = files.iter_mut().find(|f| f.2.name == info.name) {
+                        if version > existing.1 { *existing = (mtime, version, info); }
+                        continue;
+                    }
+                }
+                files.push((mtime, version, info));
             }
-            axum::Json(FilesListResp { files, bucket }).into_response()
         }
-        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"无法读取文件目录"}))).into_response(),
     }
+    if state.content_addressed {
+        // A name that maps to a hash shadows any plain file of the same name, as in `resolve_stored_name`.
+        for (name, hash) in cas::names(bucket_dir) {
+            let Ok(m) = fs::metadata(cas::blob_path(bucket_dir, &hash)) else { continue };
+            let mtime = m.modified().unwrap_or(std::time::UNIX_EPOCH);
+            files.retain(|f| f.2.name != name);
+            files.push((mtime, 0, FileInfoShort { name, size: m.len(), created: format_time(m.created().ok()), modified: format_time(Some(mtime)), bucket: bucket.to_string() }));
+        }
+    }
+    files.sort_by_key(|f| std::cmp::Reverse(f.0));
+    Ok(files.into_iter().map(|f| f.2).collect())
 }
 
-pub async fn upload_file(State(state): State<AppState>, AxPath(bucket): AxPath<String>, mut multipart: Multipart) -> impl IntoResponse {
-    let bucket_dir = state.root_dir.join(&bucket);
-    if let Err(e) = fs::create_dir_all(&bucket_dir) { return (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"创建储存桶失败","details":e.to_string()}))).into_response(); }
-    while let Ok(Some(field)) = multipart.next_field().await {
-        let name = field.name().map(|s| s.to_string()).unwrap_or_else(|| "file".to_string());
-        if name != "file" { continue; }
-        let original_name = field.file_name().map(|s| s.to_string()).unwrap_or_else(|| "upload.bin".to_string());
-        let unique = format!("{}-{}-{}", chrono::Utc::now().timestamp_millis(), rand_u32(), original_name);
-        let save_path = bucket_dir.join(&unique);
-        let bytes = match field.bytes().await { Ok(b) => b, Err(e) => { return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"文件读取失败","details":e.to_string()}))).into_response(); }};
-        if let Err(e) = tokio::fs::write(&save_path, &bytes).await { return (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"文件保存失败","details":e.to_string()}))).into_response(); }
-        let size = bytes.len() as u64;
-        let resp = UploadFileResp { success: true, file: FileInfo { name: unique.clone(), originalName: original_name, size, path: save_path.to_string_lossy().to_string(), bucket: bucket.clone() } };
-        if let Some(url) = &state.redis_url { let key = format!("{}:{}", bucket, unique); let value = serde_json::json!({"id": format!("server-{}", std::process::id()), "host": state.public_host, "port": port_from_env()}).to_string(); let _ = set_key(url, &key, &value).await; }
-        return axum::Json(resp).into_response();
-    }
-    (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"没有文件被上传"}))).into_response()
-}
-
-pub async fn download_file(State(state): State<AppState>, AxPath((bucket, filename)): AxPath<(String, String)>) -> impl IntoResponse {
-    let file_path = state.root_dir.join(&bucket).join(&filename);
+fn render_index(bucket: &str, files: &[FileInfoShort]) -> String {
+    let title = html_escape(bucket);
+    let mut rows = String::new();
+    for f in files {
+        let href = format!("/api/buckets/{}/files/{}", percent_encode_segment(bucket), percent_encode_segment(&f.name));
+        rows.push_str(&format!("<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n", html_escape(&href), html_escape(&f.name), f.size, html_escape(f.modified.as_deref().unwrap_or(""))));
+    }
+    format!("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title></head><body>\n<h1>{title}</h1>\n<table>\n<tr><th>名称</th><th>大小</th><th>修改时间</th></tr>\n{rows}</table>\n</body></html>\n")
+}
+
+/// Leading bytes kept from each upload for content-type sniffing.
+const SNIFF_LEN: usize = 16;
+
+/// `path` places the upload in a folder of the bucket (`images/2024`), created as needed.
+#[derive(Deserialize)]
+pub struct UploadQuery {
+    pub path: Option<String>,
+}
+
+pub async fn upload_file(State(state): State<AppState>, AxPath(bucket): AxPath<String>, Query(q): Query<UploadQuery>, headers: HeaderMap, mut multipart: Multipart) -> impl IntoResponse {
+    // Some clients send `Content-Length: 0` with a file field declared; say so instead of "no file".
+    if headers.get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.trim().parse::<u64>().ok()) == Some(0) {
+        return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"上传内容为空"}))).into_response();
+    }
+    let Some(bucket_dir) = state.resolve_bucket_path(&bucket) else { return invalid_bucket() };
+    let folder = q.path.as_deref().unwrap_or("").trim_matches('/');
+    if !folder.is_empty() {
+        // The folder must pass the same checks as the keys it will prefix.
+        if !is_safe_key(folder) || is_hidden(split_key(folder).1) { return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"目录路径不合法","path": folder}))).into_response(); }
+        // Names there map to hashes, not to files in folders.
+        if state.content_addressed { return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"内容寻址模式不支持目录"}))).into_response(); }
+    }
+    let _writing = BucketWriteGuard::enter(&state, &bucket);
+    if let Err(e) = fs::create_dir_all(if state.content_addressed { upload_path(&bucket_dir, "") } else { bucket_dir.join(folder) }) { return (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"创建储存桶失败","details":e.to_string()}))).into_response(); }
+    let progress = headers.get("upload-id").and_then(|v| v.to_str().ok()).filter(|v| !v.is_empty()).map(|id| {
+        let total = headers.get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok());
+        ProgressGuard::start(&state, id, total)
+    });
+    let config = load_bucket_config(&bucket_dir);
+    let metadata = merge_metadata(&config.default_metadata, metadata_from_headers(&headers));
+    // Bytes this request may still add; shrinks as each part is saved.
+    let mut quota = Quota::of(&state, &bucket_dir, &config);
+    // Every `file` part is written before any is registered; one failure undoes them all.
+    let mut saved: Vec<SavedUpload> = Vec::new();
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(f)) => f,
+            Ok(None) => break,
+            // `DefaultBodyLimit` surfaces here when the declared length is already over the limit.
+            Err(e) if e.status() == StatusCode::PAYLOAD_TOO_LARGE => { discard_uploads(&bucket_dir, saved).await; return payload_too_large(&state); }
+            Err(e) => { discard_uploads(&bucket_dir, saved).await; return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"文件读取失败","details":e.body_text()}))).into_response(); }
+        };
+        if !is_file_field(field.name(), field.file_name()) { continue; }
+        match save_upload(&state, UploadTarget { bucket: &bucket, bucket_dir: &bucket_dir, folder }, field, &metadata, progress.as_ref(), quota.as_ref()).await {
+            Ok(upload) => {
+                if let Some(q) = quota.as_mut() { if !upload.deduplicated { q.left -= upload.file.size; } }
+                saved.push(upload);
+            }
+            Err(resp) => { discard_uploads(&bucket_dir, saved).await; return resp; }
+        }
+    }
+    if saved.is_empty() { return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"没有文件被上传"}))).into_response(); }
+    let mut files = Vec::with_capacity(saved.len());
+    for upload in saved {
+        let receipt = issue_receipt(&state, &bucket, &upload.stored, upload.file.size, &upload.sha256);
+        register_location(&state, &bucket, &upload.stored, &upload.file.original_name, Some((upload.file.size, &upload.sha256))).await;
+        if let Some(r) = upload.reservation { r.confirm(&upload.stored).await; }
+        record_upload(upload.file.size);
+        files.push(UploadedFile { file: upload.file, receipt, deduplicated: upload.deduplicated });
+    }
+    axum::Json(UploadFilesResp { success: true, files }).into_response()
+}
+
+/// A part written to disk but not yet registered, with what it takes to undo it.
+struct SavedUpload {
+    file: FileInfo,
+    stored: String,
+    path: std::path::PathBuf,
+    sha256: String,
+    deduplicated: bool,
+    /// In content-addressed mode, the hash the name pointed at before this upload.
+    previous_hash: Option<Option<String>>,
+    reservation: Option<NameReservation>,
+}
+
+/// A bucket's `quotaBytes` and what a write in progress may still add to it.
+pub(crate) struct Quota {
+    limit: u64,
+    used: u64,
+    pub(crate) left: u64,
+}
+
+impl Quota {
+    /// `None` when the bucket has no quota.
+    pub(crate) fn of(state: &AppState, bucket_dir: &Path, config: &BucketConfig) -> Option<Quota> {
+        config.quota_bytes.map(|limit| { let used = bucket_usage(state, bucket_dir); Quota { limit, used, left: limit.saturating_sub(used) } })
+    }
+
+    pub(crate) fn exceeded(&self) -> axum::response::Response { self.exceeded_with(serde_json::json!({})) }
+
+    /// `exceeded`, with the fields of `extra` added to the body.
+    pub(crate) fn exceeded_with(&self, extra: serde_json::Value) -> axum::response::Response {
+        let mut body = serde_json::json!({"error":"超出储存桶配额","quotaBytes": self.limit,"usedBytes": self.used});
+        if let (Some(body), serde_json::Value::Object(extra)) = (body.as_object_mut(), extra) { body.extend(extra); }
+        (StatusCode::PAYLOAD_TOO_LARGE, axum::Json(body)).into_response()
+    }
+}
+
+/// Where a request's parts are stored; `folder` is `""` unless `?path=` was given.
+#[derive(Clone, Copy)]
+struct UploadTarget<'a> {
+    bucket: &'a str,
+    bucket_dir: &'a Path,
+    folder: &'a str,
+}
+
+/// A file on its way into a bucket: its name within the target folder, the type the client
+/// declared, and its bytes. A chunk error carries the response to fail with.
+struct Incoming<S> {
+    file_name: String,
+    declared_type: Option<String>,
+    chunks: S,
+}
+
+async fn save_upload(state: &AppState, target: UploadTarget<'_>, field: axum::extract::multipart::Field<'_>, metadata: &Metadata, progress: Option<&ProgressGuard>, quota: Option<&Quota>) -> Result<SavedUpload, axum::response::Response> {
+    // Checked on the borrowed header values, before anything is copied out of them.
+    if field.file_name().is_some_and(|n| n.len() > state.max_filename_bytes) {
+        return Err((StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"文件名过长","limit": state.max_filename_bytes}))).into_response());
+    }
+    if field.content_type().is_some_and(|c| c.len() > state.max_content_type_bytes) {
+        return Err((StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"Content-Type 过长","limit": state.max_content_type_bytes}))).into_response());
+    }
+    let file_name = field.file_name().map(|s| s.to_string()).unwrap_or_else(|| "upload.bin".to_string());
+    let declared_type = field.content_type().map(|c| c.trim().to_string()).filter(|c| !c.is_empty());
+    let chunks = field.map_err(|e| {
+        if e.status() == StatusCode::PAYLOAD_TOO_LARGE { return payload_too_large(state); }
+        (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"文件读取失败","details":e.to_string()}))).into_response()
+    });
+    store_file(state, target, Incoming { file_name, declared_type, chunks }, metadata, progress, quota).await
+}
+
+/// Writes a file the way every upload is written: size and quota limits enforced as bytes
+/// arrive, content-type sniffing, checksum, metadata and, when enabled, the content store.
+/// Nothing is registered yet.
+async fn store_file<S>(state: &AppState, target: UploadTarget<'_>, incoming: Incoming<S>, metadata: &Metadata, progress: Option<&ProgressGuard>, quota: Option<&Quota>) -> Result<SavedUpload, axum::response::Response>
+where S: futures_util::Stream<Item = Result<axum::body::Bytes, axum::response::Response>> {
+    let UploadTarget { bucket, bucket_dir, folder } = target;
+    let Incoming { file_name, declared_type, chunks } = incoming;
+    if !is_plain_name(&file_name) { return Err(invalid_filename()); }
+    // Inside a folder both names are keys: `images/logo.png` and `images/<unique>`.
+    let original_name = join_key(folder, &file_name);
+    let reservation = NameReservation::acquire(state, bucket, &original_name).await?;
+    let unique = join_key(folder, &format!("{}-{}-{}", chrono::Utc::now().timestamp_millis(), rand_u32(), file_name));
+    let save_path = if state.content_addressed { upload_path(bucket_dir, &unique) } else { bucket_dir.join(&unique) };
+    let mut out = match tokio::fs::File::create(&save_path).await { Ok(f) => f, Err(e) => { return Err((StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"文件保存失败","details":e.to_string()}))).into_response()); }};
+    let mut chunks = std::pin::pin!(chunks);
+    let mut head: Vec<u8> = Vec::new();
+    let mut hasher = Sha256::new();
+    let mut size: u64 = 0;
+    loop {
+        let chunk = match chunks.next().await {
+            Some(Ok(c)) => c,
+            None => break,
+            Some(Err(resp)) => { drop(out); let _ = tokio::fs::remove_file(&save_path).await; return Err(resp); }
+        };
+        if size + chunk.len() as u64 > state.max_upload_bytes { drop(out); let _ = tokio::fs::remove_file(&save_path).await; return Err(payload_too_large(state)); }
+        // Checked as bytes arrive, so an oversized part is cut off mid-stream rather than stored first.
+        if let Some(q) = quota.filter(|q| size + chunk.len() as u64 > q.left) {
+            drop(out); let _ = tokio::fs::remove_file(&save_path).await;
+            return Err(q.exceeded());
+        }
+        if let Err(e) = out.write_all(&chunk).await { drop(out); let _ = tokio::fs::remove_file(&save_path).await; return Err((StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"文件保存失败","details":e.to_string()}))).into_response()); }
+        if head.len() < SNIFF_LEN { head.extend_from_slice(&chunk[..chunk.len().min(SNIFF_LEN - head.len())]); }
+        hasher.update(&chunk);
+        size += chunk.len() as u64;
+        if let Some(p) = progress { p.advance(chunk.len() as u64); }
+    }
+    if let Err(e) = out.flush().await { drop(out); let _ = tokio::fs::remove_file(&save_path).await; return Err((StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"文件保存失败","details":e.to_string()}))).into_response()); }
+    drop(out);
+    let content_type = declared_type.or_else(|| sniff_content_type(&head).map(str::to_string));
+    if content_type.is_none() && state.require_content_type {
+        if !quarantine_upload(state, bucket, &save_path, &original_name, "missing content type").await { let _ = tokio::fs::remove_file(&save_path).await; }
+        return Err((StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"缺少 Content-Type，且无法识别文件类型","filename": original_name}))).into_response());
+    }
+    let sha256 = hex::encode(hasher.finalize());
+    // Content-addressed uploads are stored under their hash, once per bucket.
+    let (stored, file_path, deduplicated) = if state.content_addressed {
+        match commit_blob(bucket_dir, &save_path, &sha256).await {
+            Ok(deduplicated) => (sha256.clone(), cas::blob_path(bucket_dir, &sha256), deduplicated),
+            Err(e) => { let _ = tokio::fs::remove_file(&save_path).await; return Err((StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"文件保存失败","details":e.to_string()}))).into_response()); }
+        }
+    } else { (unique.clone(), save_path.clone(), false) };
+    let mut metadata = metadata.clone();
+    if let Some(ct) = content_type { metadata.insert(CONTENT_TYPE_KEY.to_string(), ct); }
+    if !metadata.is_empty() {
+        // A deduplicated blob belongs to earlier uploads too, so it stays.
+        if let Err(e) = save_file_metadata(bucket_dir, &stored, &metadata).await { if !deduplicated { let _ = tokio::fs::remove_file(&file_path).await; } return Err((StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"文件保存失败","details":e.to_string()}))).into_response()); }
+    }
+    if !state.content_addressed {
+        if let Err(e) = save_original_name(bucket_dir, &stored, &original_name).await { let _ = tokio::fs::remove_file(&file_path).await; remove_file_metadata(bucket_dir, &stored); return Err((StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"文件保存失败","details":e.to_string()}))).into_response()); }
+    }
+    let mut previous_hash = None;
+    if state.content_addressed {
+        previous_hash = Some(cas::hash_of(bucket_dir, &original_name));
+        if let Err(e) = link_name(bucket_dir, &original_name, &stored).await {
+            if !deduplicated { let _ = tokio::fs::remove_file(&file_path).await; remove_file_metadata(bucket_dir, &stored); }
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"文件保存失败","details":e.to_string()}))).into_response());
+        }
+    }
+    let (display_name, display_path) = if state.clean_names {
+        (original_name.clone(), format!("{}/{}", bucket, original_name))
+    } else {
+        (stored.clone(), file_path.to_string_lossy().to_string())
+    };
+    let file = FileInfo { name: display_name, original_name, size, path: display_path, bucket: bucket.to_string(), metadata };
+    Ok(SavedUpload { file, stored, path: file_path, sha256, deduplicated, previous_hash, reservation })
+}
+
+/// Bytes a bucket holds against its quota: plain files, cold files at their original size,
+/// and content-addressed blobs once each.
+fn bucket_usage(state: &AppState, bucket_dir: &Path) -> u64 {
+    let mut used = 0;
+    walk_bucket(bucket_dir, |entry| { used += entry.size(); true });
+    used + if state.content_addressed { cas::usage(bucket_dir).0 } else { 0 }
+}
+
+/// Rolls back parts saved earlier in a request that failed; dropping each reservation frees its name.
+async fn discard_uploads(bucket_dir: &Path, saved: Vec<SavedUpload>) {
+    // Newest first, so a name linked twice in one request ends up where it started.
+    for upload in saved.into_iter().rev() {
+        if let Some(previous) = &upload.previous_hash {
+            match previous {
+                Some(hash) => { let _ = link_name(bucket_dir, &upload.file.original_name, hash).await; }
+                None => cas::unlink_name(bucket_dir, &upload.file.original_name),
+            }
+        }
+        if upload.deduplicated { continue; }
+        let _ = tokio::fs::remove_file(&upload.path).await;
+        remove_file_metadata(bucket_dir, &upload.stored);
+        remove_original_name(bucket_dir, &upload.stored);
+    }
+}
+
+/// A part is the upload when it is named `file`, or when it has no name but carries a filename.
+/// Unnamed parts without a filename are ordinary form values and are skipped.
+fn is_file_field(name: Option<&str>, file_name: Option<&str>) -> bool {
+    match name {
+        Some(n) => n == "file",
+        None => file_name.is_some(),
+    }
+}
+
+/// Keeps an `Upload-Id` entry in `AppState::uploads` for the lifetime of the request.
+struct ProgressGuard {
+    uploads: std::sync::Arc<dashmap::DashMap<String, UploadProgress>>,
+    id: String,
+}
+
+impl ProgressGuard {
+    fn start(state: &AppState, id: &str, total: Option<u64>) -> Self {
+        state.uploads.insert(id.to_string(), UploadProgress { received: 0, total });
+        ProgressGuard { uploads: state.uploads.clone(), id: id.to_string() }
+    }
+
+    fn advance(&self, n: u64) {
+        if let Some(mut p) = self.uploads.get_mut(&self.id) { p.received += n; }
+    }
+}
+
+impl Drop for ProgressGuard {
+    fn drop(&mut self) { self.uploads.remove(&self.id); }
+}
+
+pub async fn upload_progress(State(state): State<AppState>, AxPath(id): AxPath<String>) -> impl IntoResponse {
+    match state.uploads.get(&id).map(|p| *p) {
+        Some(p) => {
+            let percentage = p.total.filter(|t| *t > 0).map(|t| ((p.received as f64 / t as f64) * 100.0).min(100.0));
+            with_cache_control(&state, axum::Json(serde_json::json!({"uploadId": id, "received": p.received, "total": p.total, "percentage": percentage})).into_response())
+        }
+        None => (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"上传不存在或已完成"}))).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DownloadQuery {
+    pub disposition: Option<String>,
+}
+
+/// Types a browser can render without running active content; only these honour `?disposition=inline`.
+fn is_previewable(mime: &mime::Mime) -> bool {
+    match (mime.type_(), mime.subtype().as_str()) {
+        (mime::IMAGE, sub) => sub != "svg",
+        (mime::AUDIO, _) | (mime::VIDEO, _) => true,
+        (mime::APPLICATION, "pdf") | (mime::TEXT, "plain") => true,
+        _ => false,
+    }
+}
+
+pub async fn download_file(State(state): State<AppState>, AxPath((bucket, filename)): AxPath<(String, String)>, Query(q): Query<DownloadQuery>, trace: Option<axum::Extension<TraceContext>>, headers: HeaderMap) -> impl IntoResponse {
+    if state.resolve_file_path(&bucket, &filename).is_none() { return invalid_filename(); }
+    let Some(stored) = resolve_stored_name(&state, &bucket, &filename).await else { return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"文件不存在"}))).into_response() };
+    let Some(file_path) = state.resolve_file_path(&bucket, &stored) else { return invalid_filename() };
+    // A blob has no name of its own; it is served under whatever it was asked for.
+    let served_name = if state.content_addressed && is_content_hash(&stored) { filename.clone() } else {
+        let original = state.resolve_bucket_path(&bucket).map_or_else(|| original_key(&stored), |dir| original_key_of(&dir, &stored));
+        split_key(&original).1.to_string()
+    };
+    match rehydrate(&state, &bucket, &stored, false).await {
+        Ok(Rehydration::Ready) => {}
+        Ok(Rehydration::Pending) => return (StatusCode::ACCEPTED, [(header::RETRY_AFTER, "5")], axum::Json(serde_json::json!({"status":"rehydrating","message":"文件正在从冷存储恢复，请稍后重试"}))).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"从冷存储恢复文件失败","details":e.to_string()}))).into_response(),
+    }
     if !file_path.exists() {
-        if let Some(url) = &state.redis_url { let key = format!("{}:{}", bucket, filename); if let Ok(Some(loc)) = get_key(url, &key).await { if let Ok(obj) = serde_json::from_str::<serde_json::Value>(&loc) { if let (Some(host), Some(port)) = (obj.get("host").and_then(|v| v.as_str()), obj.get("port").and_then(|v| v.as_u64())) { let target = format!("http://{}:{}/api/buckets/{}/files/{}", host, port, bucket, filename); return axum::response::Redirect::to(&target).into_response(); } } } }
-        return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"文件不存在"}))).into_response();
+        let Some((node, target)) = remote_location(&state, &bucket, &stored).await else { return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"文件不存在"}))).into_response() };
+        if let Some(id) = &node { if node_gone(&state, id).await { return node_unavailable(id); } }
+        if state.proxy_mode && !headers.contains_key(PROXIED_HEADER) { return proxy_download(&state, &target, &headers, trace.as_deref()).await; }
+        return axum::response::Redirect::to(&target).into_response();
     }
-    match tokio::fs::File::open(&file_path).await {
-        Ok(file) => { let stream = tokio_util::io::ReaderStream::new(file); let body = Body::from_stream(stream); let mut headers = HeaderMap::new(); headers.insert(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename).parse().unwrap()); (StatusCode::OK, headers, body).into_response() }
-        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"服务器内部错误"}))).into_response(),
+    let mut file = match tokio::fs::File::open(&file_path).await { Ok(f) => f, Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"服务器内部错误"}))).into_response() };
+    let len = match file.metadata().await { Ok(m) => m.len(), Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"服务器内部错误"}))).into_response() };
+    let mut resp_headers = HeaderMap::new();
+    resp_headers.insert(header::ACCEPT_RANGES, header::HeaderValue::from_static("bytes"));
+    // Guessed from the original name's extension, not from what the uploader declared.
+    let mime = mime_guess::from_path(&served_name).first_or_octet_stream();
+    let disposition = if q.disposition.as_deref() == Some("inline") && is_previewable(&mime) { "inline" } else { "attachment" };
+    if let Ok(v) = header::HeaderValue::from_str(mime.as_ref()) { resp_headers.insert(header::CONTENT_TYPE, v); }
+    resp_headers.insert(header::X_CONTENT_TYPE_OPTIONS, header::HeaderValue::from_static("nosniff"));
+    // Serve the name the file was uploaded under, not the generated on-disk name.
+    resp_headers.insert(header::CONTENT_DISPOSITION, content_disposition(disposition, &served_name));
+    // What a hash names can never change; a name can be re-pointed, so it isn't cached this way.
+    if state.content_addressed && is_content_hash(&filename) { resp_headers.insert(header::CACHE_CONTROL, header::HeaderValue::from_static("public, max-age=31536000, immutable")); }
+    match headers.get(header::RANGE).and_then(|v| v.to_str().ok()).and_then(|v| parse_byte_range(v, len)) {
+        Some(ByteRange::Unsatisfiable) => {
+            resp_headers.insert(header::CONTENT_RANGE, format!("bytes */{}", len).parse().unwrap());
+            (StatusCode::RANGE_NOT_SATISFIABLE, resp_headers).into_response()
+        }
+        Some(ByteRange::Satisfiable(start, end)) => {
+            // Seek rather than read past the skipped prefix; tail ranges of large files stay cheap.
+            if file.seek(std::io::SeekFrom::Start(start)).await.is_err() { return (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"服务器内部错误"}))).into_response(); }
+            let slice_len = end - start + 1;
+            resp_headers.insert(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len).parse().unwrap());
+            resp_headers.insert(header::CONTENT_LENGTH, slice_len.into());
+            record_download(slice_len);
+            (StatusCode::PARTIAL_CONTENT, resp_headers, Body::from_stream(tokio_util::io::ReaderStream::new(file.take(slice_len)))).into_response()
+        }
+        Some(ByteRange::Multiple(ranges)) => byteranges_response(file_path, ranges, len, mime.as_ref(), resp_headers),
+        None => {
+            resp_headers.insert(header::CONTENT_LENGTH, len.into());
+            record_download(len);
+            (StatusCode::OK, resp_headers, Body::from_stream(tokio_util::io::ReaderStream::new(file))).into_response()
+        }
     }
 }
 
+/// Whether `name` resolves to a stored file, on this node (cold or not) or, per the index,
+/// on another one.
+pub(crate) async fn file_exists(state: &AppState, bucket: &str, name: &str) -> bool {
+    let Some(stored) = resolve_stored_name(state, bucket, name).await else { return false };
+    if state.resolve_file_path(bucket, &stored).is_some_and(|p| p.is_file()) { return true; }
+    if state.resolve_bucket_path(bucket).is_some_and(|dir| cold::is_present(&dir, &stored)) { return true; }
+    remote_location(state, bucket, &stored).await.is_some()
+}
+
+/// The node holding `stored` according to its location entry, and its `http://host:port`
+/// origin. Entries recorded before node ids were stored have no node.
+pub(crate) async fn remote_node(state: &AppState, bucket: &str, stored: &str) -> Option<(Option<String>, String)> {
+    let loc = state.index.as_ref()?.get_location(bucket, stored).await.ok()??;
+    let obj: serde_json::Value = serde_json::from_str(&loc).ok()?;
+    let (host, port) = (obj.get("host")?.as_str()?, obj.get("port")?.as_u64()?);
+    let node = obj.get("id").and_then(|v| v.as_str()).map(str::to_string);
+    Some((node, format!("http://{}:{}", host, port)))
+}
+
+/// Like `remote_node`, with the URL the node serves the file from.
+async fn remote_location(state: &AppState, bucket: &str, stored: &str) -> Option<(Option<String>, String)> {
+    let (node, origin) = remote_node(state, bucket, stored).await?;
+    Some((node, format!("{}/api/buckets/{}/files/{}", origin, percent_encode_segment(bucket), percent_encode_key(stored))))
+}
+
+/// Whether node `id` has let its heartbeat key expire; don't send clients after it. One never
+/// heard from predates heartbeats (or doesn't send them) and is tried as before.
+pub(crate) async fn node_gone(state: &AppState, id: &str) -> bool {
+    let Some(index) = &state.index else { return false };
+    matches!(index.node_status(id).await, Ok(NodeStatus::Gone))
+}
+
+pub(crate) fn node_unavailable(id: &str) -> axum::response::Response {
+    (StatusCode::SERVICE_UNAVAILABLE, axum::Json(serde_json::json!({"error":"文件所在节点不可用","node": id}))).into_response()
+}
+
+/// `206 multipart/byteranges`: one part per range, each with its own `Content-Range`.
+/// Parts are read lazily, each through its own handle, so memory use doesn't depend on range sizes.
+fn byteranges_response(path: std::path::PathBuf, ranges: Vec<(u64, u64)>, len: u64, content_type: &str, mut resp_headers: HeaderMap) -> axum::response::Response {
+    let boundary = format!("{:08x}{:08x}", rand_u32(), rand_u32());
+    let heads: Vec<String> = ranges.iter().enumerate()
+        .map(|(i, (start, end))| format!("{}--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n", if i == 0 { "" } else { "\r\n" }, boundary, content_type, start, end, len))
+        .collect();
+    let tail = format!("\r\n--{}--\r\n", boundary);
+    let total: u64 = heads.iter().map(|h| h.len() as u64).sum::<u64>() + ranges.iter().map(|(s, e)| e - s + 1).sum::<u64>() + tail.len() as u64;
+    let parts = heads.into_iter().zip(ranges).map(move |(head, (start, end))| {
+        let path = path.clone();
+        let body = futures_util::stream::once(async move {
+            let mut f = tokio::fs::File::open(&path).await?;
+            f.seek(std::io::SeekFrom::Start(start)).await?;
+            Ok::<_, std::io::Error>(tokio_util::io::ReaderStream::new(f.take(end - start + 1)))
+        }).try_flatten();
+        futures_util::stream::once(async move { Ok(axum::body::Bytes::from(head)) }).chain(body)
+    });
+    let stream = futures_util::stream::iter(parts).flatten().chain(futures_util::stream::once(async move { Ok(axum::body::Bytes::from(tail)) }));
+    if let Ok(v) = format!("multipart/byteranges; boundary={}", boundary).parse() { resp_headers.insert(header::CONTENT_TYPE, v); }
+    resp_headers.insert(header::CONTENT_LENGTH, total.into());
+    record_download(total);
+    (StatusCode::PARTIAL_CONTENT, resp_headers, Body::from_stream(stream)).into_response()
+}
+
 pub async fn delete_file(State(state): State<AppState>, AxPath((bucket, filename)): AxPath<(String, String)>) -> impl IntoResponse {
-    let file_path = state.root_dir.join(&bucket).join(&filename);
-    if !file_path.exists() { return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"文件不存在"}))).into_response(); }
-    match fs::remove_file(&file_path) {
-        Ok(_) => { if let Some(url) = &state.redis_url { let key = format!("{}:{}", bucket, filename); let _ = del_key(url, &key).await; } axum::Json(serde_json::json!({"message":"文件删除成功"})).into_response() }
+    if state.resolve_file_path(&bucket, &filename).is_none() { return invalid_filename(); }
+    let Some(stored) = resolve_stored_name(&state, &bucket, &filename).await else { return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"文件不存在"}))).into_response() };
+    let Some(file_path) = state.resolve_file_path(&bucket, &stored) else { return invalid_filename() };
+    let Some(bucket_dir) = state.resolve_bucket_path(&bucket) else { return invalid_bucket() };
+    if state.content_addressed && is_content_hash(&stored) { return delete_content_addressed(&state, &bucket, &bucket_dir, &filename, &stored).await; }
+    let removed = if file_path.exists() { fs::remove_file(&file_path) } else if discard(&state, &bucket, &bucket_dir, &stored).await { Ok(()) } else {
+        return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"文件不存在"}))).into_response();
+    };
+    match removed {
+        Ok(_) => {
+            forget_file(&state, &bucket, &bucket_dir, &stored).await;
+            record_delete();
+            axum::Json(serde_json::json!({"message":"文件删除成功"})).into_response()
+        }
         Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error": format!("文件删除失败: {}", e)}))).into_response(),
     }
 }
 
-pub async fn file_info(State(state): State<AppState>, AxPath((bucket, filename)): AxPath<(String, String)>) -> impl IntoResponse {
-    let file_path = state.root_dir.join(&bucket).join(&filename);
-    match fs::metadata(&file_path) {
-        Ok(m) => {
-            let mut obj = serde_json::json!({"filename": filename, "size": m.len(), "createdAt": format_time(m.created().ok()), "modifiedAt": format_time(m.modified().ok()), "bucket": bucket});
-            if let Some(url) = &state.redis_url { let key = format!("{}:{}", bucket, filename); if let Ok(Some(loc)) = get_key(url, &key).await { obj["location"] = serde_json::from_str::<serde_json::Value>(&loc).unwrap_or(serde_json::Value::Null); } }
-            axum::Json(obj).into_response()
+/// Deleting a name drops just that name, keeping the blob while other names still point at
+/// it; deleting by hash removes the blob along with every name pointing at it.
+async fn delete_content_addressed(state: &AppState, bucket: &str, bucket_dir: &Path, filename: &str, hash: &str) -> axum::response::Response {
+    let names = if filename == hash { cas::names_for(bucket_dir, hash) } else { vec![filename.to_string()] };
+    for name in &names { forget_name(state, bucket, bucket_dir, name, hash).await; }
+    if !cas::names_for(bucket_dir, hash).is_empty() {
+        bump_generation(state, bucket);
+        record_delete();
+        return axum::Json(serde_json::json!({"message":"文件删除成功","retained": true})).into_response();
+    }
+    match fs::remove_file(cas::blob_path(bucket_dir, hash)) {
+        Ok(_) => {
+            forget_file(state, bucket, bucket_dir, hash).await;
+            record_delete();
+            axum::Json(serde_json::json!({"message":"文件删除成功"})).into_response()
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => { bump_generation(state, bucket); (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"文件不存在"}))).into_response() }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error": format!("文件删除失败: {}", e)}))).into_response(),
+    }
+}
+
+/// Drops one name of a content-addressed file from the local mapping, the `names:<bucket>`
+/// index and its reservation, wherever they still point at `hash`.
+async fn forget_name(state: &AppState, bucket: &str, bucket_dir: &Path, name: &str, hash: &str) {
+    if cas::hash_of(bucket_dir, name).as_deref() == Some(hash) { cas::unlink_name(bucket_dir, name); }
+    let Some(redis) = &state.redis else { return };
+    if let Ok(Some(current)) = hget(redis, &names_key(bucket), name).await { if current == hash { let _ = hdel(redis, &names_key(bucket), name).await; } }
+    if state.name_reservation {
+        let key = reservation_key(bucket, name);
+        if let Ok(Some(owner)) = get_key(redis, &key).await { if owner == hash { let _ = del_key(redis, &key).await; } }
+    }
+}
+
+/// Drops everything recorded about a file that has left `bucket`: its sidecars, its location
+/// key, its name-index entry (falling back to an older version) and any name reservation.
+async fn forget_file(state: &AppState, bucket: &str, bucket_dir: &Path, stored: &str) {
+    bump_generation(state, bucket);
+    let original = original_key_of(bucket_dir, stored);
+    remove_file_metadata(bucket_dir, stored);
+    remove_original_name(bucket_dir, stored);
+    remove_sidecar(bucket_dir, stored);
+    if let Some(index) = &state.index { let _ = index.del_location(bucket, stored).await; }
+    let Some(redis) = &state.redis else { return };
+    if let Ok(Some(current)) = hget(redis, &names_key(bucket), &original).await {
+        // Fall back to the previous version, if this node still has one.
+        if current == stored {
+            match versions_of(bucket_dir, &original).first() {
+                Some(previous) => { let _ = hset(redis, &names_key(bucket), &original, previous).await; }
+                None => { let _ = hdel(redis, &names_key(bucket), &original).await; }
+            }
+        }
+    }
+    if state.name_reservation {
+        let key = reservation_key(bucket, &original);
+        if let Ok(Some(owner)) = get_key(redis, &key).await { if owner == stored { let _ = del_key(redis, &key).await; } }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MoveReq {
+    #[serde(rename = "targetBucket")]
+    pub target_bucket: String,
+    #[serde(default)]
+    pub copy: bool,
+}
+
+/// Moves (or with `copy`, copies) a file into another bucket under the same stored name,
+/// carrying its metadata and checksum along and re-pointing its location key.
+pub async fn move_file(State(state): State<AppState>, AxPath((bucket, filename)): AxPath<(String, String)>, axum::Json(req): axum::Json<MoveReq>) -> impl IntoResponse {
+    if state.resolve_file_path(&bucket, &filename).is_none() { return invalid_filename(); }
+    if let Err(msg) = validate_bucket_name(&req.target_bucket, state.s3_compat_names) { return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error": msg}))).into_response(); }
+    let (Some(bucket_dir), Some(target_dir)) = (state.resolve_bucket_path(&bucket), state.resolve_bucket_path(&req.target_bucket)) else { return invalid_bucket() };
+    let Some(stored) = resolve_stored_name(&state, &bucket, &filename).await else { return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"文件不存在"}))).into_response() };
+    if state.content_addressed && is_content_hash(&stored) { return (StatusCode::CONFLICT, axum::Json(serde_json::json!({"error":"内容寻址的文件不支持移动，请重新上传到目标储存桶"}))).into_response(); }
+    if let Err(e) = rehydrate(&state, &bucket, &stored, true).await { return (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"从冷存储恢复文件失败","details":e.to_string()}))).into_response(); }
+    let source = bucket_dir.join(&stored);
+    if !source.is_file() { return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"文件不存在"}))).into_response(); }
+    let original = original_key_of(&bucket_dir, &stored);
+    let name = if state.clean_names { original.clone() } else { stored.clone() };
+    if req.target_bucket == bucket { return axum::Json(serde_json::json!({"success": true, "file": {"name": name, "bucket": bucket}, "copied": false, "unchanged": true})).into_response(); }
+
+    let _writing = BucketWriteGuard::enter(&state, &req.target_bucket);
+    let target = target_dir.join(&stored);
+    // A nested key keeps its folders in the target bucket.
+    if let Err(e) = fs::create_dir_all(target.parent().unwrap_or(&target_dir)) { return (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"创建储存桶失败","details":e.to_string()}))).into_response(); }
+    if cold::is_present(&target_dir, &stored) { return (StatusCode::CONFLICT, axum::Json(serde_json::json!({"error":"目标储存桶中已存在同名文件"}))).into_response(); }
+    let incoming = fs::metadata(&source).map(|m| m.len()).unwrap_or(0);
+    if let Some(q) = Quota::of(&state, &target_dir, &load_bucket_config(&target_dir)).filter(|q| incoming > q.left) { return q.exceeded(); }
+    // Serialized with appends, rotation and demotion of the source.
+    let lock = state.file_locks.entry(source.clone()).or_default().clone();
+    let guard = lock.lock().await;
+    let result = if req.copy { tokio::fs::copy(&source, &target).await.map(|_| ()) } else { move_across(&source, &target).await };
+    drop(guard);
+    drop(lock);
+    state.file_locks.remove_if(&source, |_, l| std::sync::Arc::strong_count(l) == 1);
+    if let Err(e) = result { return (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error": if req.copy { "文件复制失败" } else { "文件移动失败" }, "details": e.to_string()}))).into_response(); }
+
+    if let Some(meta) = load_file_metadata(&bucket_dir, &stored) { let _ = save_file_metadata(&target_dir, &stored, &meta).await; }
+    let _ = save_original_name(&target_dir, &stored, &original).await;
+    let checksum = load_checksum(&state, &bucket, &stored).await;
+    let size = fs::metadata(&target).map(|m| m.len()).unwrap_or(0);
+    register_location(&state, &req.target_bucket, &stored, &original, checksum.as_deref().map(|c| (size, c))).await;
+    if !req.copy { forget_file(&state, &bucket, &bucket_dir, &stored).await; }
+    axum::Json(serde_json::json!({"success": true, "file": {"name": name, "bucket": req.target_bucket}, "copied": req.copy})).into_response()
+}
+
+/// `rename`, falling back to copy-then-delete when the buckets sit on different filesystems.
+async fn move_across(source: &Path, target: &Path) -> std::io::Result<()> {
+    match tokio::fs::rename(source, target).await {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            if let Err(e) = tokio::fs::copy(source, target).await { let _ = tokio::fs::remove_file(target).await; return Err(e); }
+            tokio::fs::remove_file(source).await
         }
-        Err(_) => (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"文件不存在"}))).into_response(),
+        other => other,
     }
 }
 
+pub async fn file_info(State(state): State<AppState>, AxPath((bucket, filename)): AxPath<(String, String)>, headers: HeaderMap) -> impl IntoResponse {
+    if state.resolve_file_path(&bucket, &filename).is_none() { return invalid_filename(); }
+    let etag = generation_etag(&state, Some(&bucket));
+    cached(&state, &headers, etag, async {
+        let Some(stored) = resolve_stored_name(&state, &bucket, &filename).await else { return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"文件不存在"}))).into_response(); };
+        let Some(file_path) = state.resolve_file_path(&bucket, &stored) else { return invalid_filename() };
+        match fs::metadata(&file_path) {
+            Ok(m) => {
+                let mut obj = serde_json::json!({"filename": filename, "size": m.len(), "createdAt": format_time(m.created().ok()), "modifiedAt": format_time(m.modified().ok()), "bucket": bucket});
+                if let Some(meta) = state.resolve_bucket_path(&bucket).and_then(|dir| load_file_metadata(&dir, &stored)) { obj["metadata"] = serde_json::json!(meta); }
+                obj["checksum"] = load_checksum(&state, &bucket, &stored).await.into();
+                if let Some(index) = &state.index { if let Ok(Some(loc)) = index.get_location(&bucket, &stored).await { obj["location"] = serde_json::from_str::<serde_json::Value>(&loc).unwrap_or(serde_json::Value::Null); } }
+                axum::Json(obj).into_response()
+            }
+            Err(_) => match state.resolve_bucket_path(&bucket).and_then(|dir| load_marker(&dir, &stored)) {
+                // Reported without rehydrating; the next download restores it.
+                Some(marker) => axum::Json(serde_json::json!({"filename": filename, "size": marker.size, "createdAt": None::<String>, "modifiedAt": format_time(Some(std::time::UNIX_EPOCH + Duration::from_secs(marker.modified))), "bucket": bucket, "cold": true})).into_response(),
+                None => (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"文件不存在"}))).into_response(),
+            },
+        }
+    }).await
+}
+
 #[derive(Deserialize)]
-pub struct NodeRegisterReq { pub id: Option<String>, pub host: Option<String>, pub port: Option<u16> }
+pub struct ConcatReq {
+    pub filenames: Vec<String>,
+    #[serde(rename = "outputName")]
+    pub output_name: Option<String>,
+    #[serde(rename = "onMissing", default)]
+    pub on_missing: OnMissing,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnMissing { #[default] Error, Skip }
+
+#[derive(Deserialize)]
+pub struct ConcatQuery {
+    #[serde(default)]
+    pub download: bool,
+}
+
+pub async fn concat_files(State(state): State<AppState>, AxPath(bucket): AxPath<String>, Query(q): Query<ConcatQuery>, axum::Json(payload): axum::Json<ConcatReq>) -> impl IntoResponse {
+    let Some(bucket_dir) = state.resolve_bucket_path(&bucket) else { return invalid_bucket() };
+    if !bucket_dir.exists() { return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"储存桶不存在"}))).into_response(); }
+    if payload.filenames.is_empty() { return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"文件列表不能为空"}))).into_response(); }
+    if let Some(bad) = payload.filenames.iter().chain(payload.output_name.iter()).find(|n| state.resolve_file_path(&bucket, n).is_none()) {
+        return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"文件名不合法","filename": bad}))).into_response();
+    }
+    let output_name = match (&payload.output_name, q.download) {
+        (Some(n), _) => n.clone(),
+        (None, true) => "concat.bin".to_string(),
+        (None, false) => return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"缺少输出文件名"}))).into_response(),
+    };
+
+    // Open every member up front so a missing file is reported before any bytes are sent.
+    let mut reader: Box<dyn tokio::io::AsyncRead + Unpin + Send> = Box::new(tokio::io::empty());
+    let mut parts = Vec::new();
+    let mut skipped = Vec::new();
+    for name in &payload.filenames {
+        let opened = match resolve_stored_name(&state, &bucket, name).await {
+            Some(stored) => match state.resolve_file_path(&bucket, &stored) { Some(path) => tokio::fs::File::open(path).await, None => Err(std::io::ErrorKind::NotFound.into()) },
+            None => Err(std::io::ErrorKind::NotFound.into()),
+        };
+        match opened {
+            Ok(f) => { reader = Box::new(reader.chain(f)); parts.push(name.clone()); }
+            Err(_) if payload.on_missing == OnMissing::Skip => skipped.push(name.clone()),
+            Err(_) => return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"文件不存在","filename": name}))).into_response(),
+        }
+    }
+
+    if q.download {
+        let body = Body::from_stream(tokio_util::io::ReaderStream::new(reader));
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_DISPOSITION, content_disposition("attachment", &output_name));
+        return (StatusCode::OK, headers, body).into_response();
+    }
+
+    let (folder, leaf) = split_key(&output_name);
+    if state.content_addressed && !folder.is_empty() { return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"内容寻址模式不支持目录"}))).into_response(); }
+    let _writing = BucketWriteGuard::enter(&state, &bucket);
+    if let Err(e) = fs::create_dir_all(if state.content_addressed { upload_path(&bucket_dir, "") } else { bucket_dir.join(folder) }) { return (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"文件保存失败","details":e.to_string()}))).into_response(); }
+    let config = load_bucket_config(&bucket_dir);
+    let quota = Quota::of(&state, &bucket_dir, &config);
+    // Stored like an upload of the joined bytes, typed by the output name where it tells.
+    let incoming = Incoming {
+        file_name: leaf.to_string(),
+        declared_type: mime_guess::from_path(leaf).first().map(|m| m.to_string()),
+        chunks: tokio_util::io::ReaderStream::new(reader).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"文件读取失败","details":e.to_string()}))).into_response()),
+    };
+    let upload = match store_file(&state, UploadTarget { bucket: &bucket, bucket_dir: &bucket_dir, folder }, incoming, &config.default_metadata, None, quota.as_ref()).await { Ok(u) => u, Err(resp) => return resp };
+    register_location(&state, &bucket, &upload.stored, &upload.file.original_name, Some((upload.file.size, &upload.sha256))).await;
+    if let Some(r) = upload.reservation { r.confirm(&upload.stored).await; }
+    axum::Json(serde_json::json!({"success": true, "file": upload.file, "deduplicated": upload.deduplicated, "parts": parts, "skipped": skipped})).into_response()
+}
+
+/// Appends the raw request body to a file, creating it when absent. Appends to the same
+/// file are serialized so concurrent writers can't interleave their bytes.
+pub async fn append_file(State(state): State<AppState>, AxPath((bucket, filename)): AxPath<(String, String)>, body: Body) -> impl IntoResponse {
+    if state.resolve_file_path(&bucket, &filename).is_none() { return invalid_filename(); }
+    let Some(bucket_dir) = state.resolve_bucket_path(&bucket) else { return invalid_bucket() };
+    // A bucket the append creates is held to the same naming rules as `POST /api/buckets`.
+    if !bucket_dir.exists() {
+        if let Err(msg) = validate_bucket_name(&bucket, state.s3_compat_names) { return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error": msg}))).into_response(); }
+    }
+    let _writing = BucketWriteGuard::enter(&state, &bucket);
+    let (folder, leaf) = split_key(&filename);
+    if let Err(e) = fs::create_dir_all(bucket_dir.join(folder)) { return (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"创建储存桶失败","details":e.to_string()}))).into_response(); }
+    let Some(resolved) = resolve_stored_name(&state, &bucket, &filename).await else { return invalid_filename() };
+    if state.content_addressed && is_content_hash(&resolved) { return (StatusCode::CONFLICT, axum::Json(serde_json::json!({"error":"内容寻址的文件不可修改"}))).into_response(); }
+    if let Err(e) = rehydrate(&state, &bucket, &resolved, true).await { return (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"从冷存储恢复文件失败","details":e.to_string()}))).into_response(); }
+    // In clean-name mode a new log still gets the usual unique on-disk name.
+    let stored = if state.clean_names && !bucket_dir.join(&resolved).is_file() {
+        join_key(folder, &format!("{}-{}-{}", chrono::Utc::now().timestamp_millis(), rand_u32(), leaf))
+    } else { resolved };
+    let path = bucket_dir.join(&stored);
+    let quota = Quota::of(&state, &bucket_dir, &load_bucket_config(&bucket_dir));
+
+    let lock = state.file_locks.entry(path.clone()).or_default().clone();
+    let guard = lock.lock().await;
+    let rotated = rotate_if_needed(&state, &path, quota.as_ref()).await;
+    let result = match &rotated {
+        Ok(_) => append_locked(&state, &path, body, quota.as_ref()).await,
+        Err(e) if e.kind() == std::io::ErrorKind::QuotaExceeded => Err(quota.as_ref().map_or_else(|| payload_too_large(&state), Quota::exceeded)),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"日志轮转失败","details":e.to_string()}))).into_response()),
+    };
+    // Hashed before the lock is released, so the digest covers exactly these bytes.
+    let digest = match &result { Ok(_) => sha256_file(&path).await.ok(), Err(_) => None };
+    drop(guard);
+    drop(lock);
+    state.file_locks.remove_if(&path, |_, l| std::sync::Arc::strong_count(l) == 1);
+
+    // A segment is named after the file it was rotated out of: `<original>.N.gz`.
+    let rotated = rotated.ok().flatten().map(|segment| join_key(split_key(&stored).0, &segment)).map(|segment| { let original = format!("{}{}", original_key_of(&bucket_dir, &stored), &segment[stored.len()..]); (segment, original) });
+    if let Some((segment, original)) = &rotated {
+        let _ = save_original_name(&bucket_dir, segment, original).await;
+        register_location(&state, &bucket, segment, original, None).await;
+    }
+    match result {
+        Ok((created, appended, size)) => {
+            if created { let _ = save_original_name(&bucket_dir, &stored, &filename).await; }
+            // An unreadable file keeps no digest rather than a stale one.
+            if digest.is_none() { clear_checksum(&state, &bucket, &stored).await; }
+            register_location(&state, &bucket, &stored, &original_key_of(&bucket_dir, &stored), digest.as_deref().map(|sha| (size, sha))).await;
+            let name = if state.clean_names { filename } else { stored };
+            let rotated = rotated.map(|(segment, original)| if state.clean_names { original } else { segment });
+            axum::Json(serde_json::json!({"success": true, "appended": appended, "rotated": rotated, "file": {"name": name, "size": size, "bucket": bucket}})).into_response()
+        }
+        Err(resp) => resp,
+    }
+}
+
+/// Returns `(created, appended, new_size)`. On failure the file is cut back to its previous length.
+async fn append_locked(state: &AppState, path: &Path, body: Body, quota: Option<&Quota>) -> Result<(bool, u64, u64), axum::response::Response> {
+    let created = !path.exists();
+    let mut out = match tokio::fs::OpenOptions::new().create(true).append(true).open(path).await { Ok(f) => f, Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"文件打开失败","details":e.to_string()}))).into_response()) };
+    let start = match out.metadata().await { Ok(m) => m.len(), Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"文件打开失败","details":e.to_string()}))).into_response()) };
+    let rollback = |out: tokio::fs::File| async move {
+        let _ = out.set_len(start).await;
+        if created { let _ = tokio::fs::remove_file(path).await; }
+    };
+    let mut appended: u64 = 0;
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk { Ok(c) => c, Err(e) => { rollback(out).await; return Err((StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"请求体读取失败","details":e.to_string()}))).into_response()); } };
+        if start + appended + chunk.len() as u64 > state.max_upload_bytes {
+            rollback(out).await;
+            return Err(payload_too_large(state));
+        }
+        if let Some(q) = quota.filter(|q| appended + chunk.len() as u64 > q.left) { rollback(out).await; return Err(q.exceeded()); }
+        if let Err(e) = out.write_all(&chunk).await { rollback(out).await; return Err((StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"文件保存失败","details":e.to_string()}))).into_response()); }
+        appended += chunk.len() as u64;
+    }
+    if let Err(e) = out.flush().await { rollback(out).await; return Err((StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"文件保存失败","details":e.to_string()}))).into_response()); }
+    Ok((created, appended, start + appended))
+}
+
+/// Records where a freshly stored file lives so other nodes can redirect to it.
+/// `checksum` is `(size, hex sha256)` when the bytes were hashed on the way in; when the
+/// index is disabled or unreachable the digest goes to a sidecar instead.
+pub(crate) async fn register_location(state: &AppState, bucket: &str, unique: &str, original_name: &str, checksum: Option<(u64, &str)>) {
+    bump_generation(state, bucket);
+    let mut recorded = false;
+    if let Some(index) = &state.index {
+        let mut value = serde_json::json!({"id": state.node_id, "host": state.public_host, "port": port_from_env(), "originalName": original_name});
+        if let Some((size, sha256)) = checksum { value["size"] = size.into(); value["sha256"] = sha256.into(); }
+        recorded = index.set_location(bucket, unique, &value.to_string()).await.is_ok();
+    }
+    if let Some(redis) = &state.redis { let _ = hset(redis, &names_key(bucket), original_name, unique).await; }
+    if let (false, Some((_, sha256)), Some(dir)) = (recorded, checksum, state.resolve_bucket_path(bucket)) {
+        save_sidecar(&dir, unique, sha256).await;
+    }
+}
+
+fn invalid_bucket() -> axum::response::Response {
+    (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"储存桶名称不合法"}))).into_response()
+}
+
+pub(crate) fn payload_too_large(state: &AppState) -> axum::response::Response {
+    (StatusCode::PAYLOAD_TOO_LARGE, axum::Json(serde_json::json!({"error":"文件大小超过限制","limit": state.max_upload_bytes}))).into_response()
+}
+
+fn invalid_filename() -> axum::response::Response {
+    (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"文件名不合法"}))).into_response()
+}
+
+fn names_key(bucket: &str) -> String { format!("names:{}", bucket) }
+
+fn reservation_key(bucket: &str, name: &str) -> String { format!("name:{}:{}", bucket, name) }
+
+/// How long an unconfirmed reservation survives if this node dies mid-upload.
+const RESERVATION_TTL_SECS: u64 = 3600;
+
+/// Cluster-wide claim on a clean name while an upload is in flight (`NAME_RESERVATION=true`).
+/// Dropping it without `confirm` releases the claim, so aborted uploads free the name.
+struct NameReservation {
+    redis: RedisPool,
+    key: String,
+    confirmed: bool,
+}
+
+impl NameReservation {
+    /// `Ok(None)` when reservations are off; `Err` carries the `409`/`503` to send back.
+    async fn acquire(state: &AppState, bucket: &str, name: &str) -> Result<Option<Self>, axum::response::Response> {
+        if !state.name_reservation { return Ok(None); }
+        let Some(redis) = &state.redis else { return Ok(None) };
+        let key = reservation_key(bucket, name);
+        let owner = format!("pending:{}", state.node_id);
+        match set_nx_ex(redis, &key, &owner, RESERVATION_TTL_SECS).await {
+            Ok(true) => Ok(Some(NameReservation { redis: redis.clone(), key, confirmed: false })),
+            Ok(false) => Err((StatusCode::CONFLICT, axum::Json(serde_json::json!({"error":"文件名已被占用","filename": name}))).into_response()),
+            Err(e) => Err((StatusCode::SERVICE_UNAVAILABLE, axum::Json(serde_json::json!({"error":"文件名预留失败","details": e.to_string()}))).into_response()),
+        }
+    }
+
+    /// Makes the claim permanent, pointing at the stored name; deleting that file releases it.
+    async fn confirm(mut self, stored: &str) {
+        self.confirmed = true;
+        let _ = set_key(&self.redis, &self.key, stored).await;
+    }
+}
+
+impl Drop for NameReservation {
+    fn drop(&mut self) {
+        if self.confirmed { return; }
+        let (redis, key) = (self.redis.clone(), self.key.clone());
+        tokio::spawn(async move { let _ = del_key(&redis, &key).await; });
+    }
+}
+
+/// Maps a client-facing name to the on-disk `timestamp-rand-original` name.
+/// Outside clean-name mode, or when the name already exists on disk, it is returned as-is.
+/// Otherwise the Redis `names:<bucket>` index is consulted, falling back to the newest
+/// matching file in the bucket directory. Sidecar names never resolve, and index entries
+/// that aren't plain names are ignored.
+pub(crate) async fn resolve_stored_name(state: &AppState, bucket: &str, name: &str) -> Option<String> {
+    if name.split('/').any(is_hidden) { return None; }
+    if state.content_addressed {
+        if is_content_hash(name) { return Some(name.to_string()); }
+        if let Some(hash) = content_hash_of(state, bucket, name).await { return Some(hash); }
+    }
+    if !state.clean_names { return Some(name.to_string()); }
+    let bucket_dir = state.resolve_bucket_path(bucket)?;
+    if cold::is_present(&bucket_dir, name) { return Some(name.to_string()); }
+    if let Some(redis) = &state.redis {
+        if let Some(stored) = hget(redis, &names_key(bucket), name).await.ok().flatten().filter(|n| is_safe_key(n)) {
+            if cold::is_present(&bucket_dir, &stored) { return Some(stored); }
+            // The location may still point to another node.
+            if let Some(index) = &state.index { if let Ok(Some(_)) = index.get_location(bucket, &stored).await { return Some(stored); } }
+        }
+    }
+    Some(versions_of(&bucket_dir, name).into_iter().next().unwrap_or_else(|| name.to_string()))
+}
+
+/// The hash a name points at in content-addressed mode: the `names:<bucket>` index when its
+/// entry is a hash whose blob exists, else the bucket's local mapping.
+async fn content_hash_of(state: &AppState, bucket: &str, name: &str) -> Option<String> {
+    let bucket_dir = state.resolve_bucket_path(bucket)?;
+    if let Some(redis) = &state.redis {
+        if let Some(hash) = hget(redis, &names_key(bucket), name).await.ok().flatten().filter(|h| is_content_hash(h)) {
+            if cas::blob_path(&bucket_dir, &hash).is_file() { return Some(hash); }
+            if let Some(index) = &state.index { if let Ok(Some(_)) = index.get_location(bucket, &hash).await { return Some(hash); } }
+        }
+    }
+    cas::hash_of(&bucket_dir, name).filter(|h| cas::blob_path(&bucket_dir, h).is_file())
+}
+
+/// Stored keys in `bucket_dir` whose original key is `original`, newest first; a nested
+/// key's versions are looked for in its own folder.
+fn versions_of(bucket_dir: &Path, original: &str) -> Vec<String> {
+    let (folder, original) = split_key(original);
+    let dir = bucket_dir.join(folder);
+    let Ok(iter) = fs::read_dir(&dir) else { return Vec::new() };
+    let mut names: Vec<String> = iter
+        .filter_map(Result::ok)
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .map(|n| cold::stored_from_marker(&n).map(str::to_string).unwrap_or(n))
+        .filter(|n| !is_hidden(n) && n != original && original_key_of(&dir, n) == original)
+        .collect();
+    names.sort_by_key(|n| std::cmp::Reverse(unique_timestamp(n).unwrap_or(0)));
+    names.into_iter().map(|n| join_key(folder, &n)).collect()
+}
+
+#[derive(Deserialize)]
+pub struct ResolveQuery {
+    pub name: String,
+}
+
+/// Looks up stored names by original filename via the `names:<bucket>` index,
+/// scanning the bucket directory when Redis has no answer.
+pub async fn resolve_name(State(state): State<AppState>, AxPath(bucket): AxPath<String>, Query(q): Query<ResolveQuery>) -> impl IntoResponse {
+    if !is_safe_key(&q.name) { return invalid_filename(); }
+    let Some(bucket_dir) = state.resolve_bucket_path(&bucket) else { return invalid_bucket() };
+    let versions = versions_of(&bucket_dir, &q.name);
+    let mut current = None;
+    if let Some(redis) = &state.redis {
+        if let Ok(Some(stored)) = hget(redis, &names_key(&bucket), &q.name).await { current = Some(stored); }
+    }
+    let current = current.or_else(|| versions.first().cloned());
+    match current {
+        Some(current) => with_cache_control(&state, axum::Json(serde_json::json!({"name": q.name, "bucket": bucket, "current": current, "versions": versions})).into_response()),
+        None => (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"文件不存在"}))).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NodeRegisterReq {
+    pub id: Option<String>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+}
+
+/// Unauthenticated landing response at `/`; HTML for browsers, JSON otherwise.
+/// Only static build information is exposed. Disabled with `LANDING_PAGE=false`.
+pub async fn landing(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    if !state.landing_page { return StatusCode::NOT_FOUND.into_response(); }
+    let (name, version) = (env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    let wants_html = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()).is_some_and(|v| v.contains("text/html"));
+    if wants_html {
+        return axum::response::Html(format!("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{name}</title></head><body>\n<h1>{name} {version}</h1>\n<ul><li><a href=\"/health\">/health</a></li><li><a href=\"/health/status\">/health/status</a></li></ul>\n</body></html>\n")).into_response();
+    }
+    axum::Json(serde_json::json!({"name": name, "version": version, "links": {"health": "/health", "status": "/health/status"}})).into_response()
+}
 
 pub async fn health() -> impl IntoResponse { axum::Json(serde_json::json!({"status":"ok"})) }
 
+/// Readiness as well as status: `503` when `ROOT_DIR` can't be written or is below
+/// `MIN_FREE_BYTES`, with the failing checks listed. Redis is reported but never fails it.
+pub async fn health_status(State(state): State<AppState>) -> impl IntoResponse {
+    let redis = match &state.redis {
+        Some(redis) => match ping(redis).await { Ok(ok) => serde_json::json!({"connected":ok, "pooled":redis.is_initialized()}), Err(e) => serde_json::json!({"connected":false, "pooled":redis.is_initialized(), "error": e.to_string()}) },
+        None => serde_json::json!({"disabled": true}),
+    };
+    let connections = serde_json::json!({"active": state.active_connections(), "max": state.max_connections});
+    let amplification = serde_json::json!({
+        "compressionSkipped": state.amplification.compression_skipped.load(std::sync::atomic::Ordering::Relaxed),
+        "decompressionAborted": state.amplification.decompression_aborted.load(std::sync::atomic::Ordering::Relaxed),
+    });
+    let (storage, failing) = storage_status(&state).await;
+    if !failing.is_empty() {
+        return (StatusCode::SERVICE_UNAVAILABLE, axum::Json(serde_json::json!({"status":"unavailable","error":"存储不可用","failing":failing,"storage":storage,"redis":redis,"connections":connections,"amplification":amplification}))).into_response();
+    }
+    axum::Json(serde_json::json!({"status":"ok","storage":storage,"redis":redis,"connections":connections,"amplification":amplification})).into_response()
+}
+
+/// Writes and removes a hidden probe file in `ROOT_DIR` and reads its free space. Returns
+/// the report and the names of the checks that failed.
+async fn storage_status(state: &AppState) -> (serde_json::Value, Vec<&'static str>) {
+    let probe = state.root_dir.join(format!(".health-{}-{:x}.tmp", std::process::id(), rand_u32()));
+    let written = match tokio::fs::write(&probe, b"ok").await { Ok(()) => tokio::fs::remove_file(&probe).await, Err(e) => Err(e) };
+    let root = state.root_dir.clone();
+    let available = tokio::task::spawn_blocking(move || fs2::available_space(root)).await.map_err(std::io::Error::other).and_then(|r| r);
+    let mut storage = serde_json::json!({"writable": written.is_ok(), "availableBytes": available.as_ref().ok(), "minFreeBytes": state.min_free_bytes});
+    let mut failing = Vec::new();
+    if let Err(e) = &written { storage["writeError"] = e.to_string().into(); failing.push("writable"); }
+    match &available {
+        Ok(bytes) if *bytes < state.min_free_bytes => failing.push("freeSpace"),
+        Ok(_) => {}
+        // Free space that can't be read can't be vouched for either.
+        Err(e) => { storage["spaceError"] = e.to_string().into(); if state.min_free_bytes > 0 { failing.push("freeSpace"); } }
+    }
+    (storage, failing)
+}
+
+pub async fn structure(State(state): State<AppState>) -> impl IntoResponse {
+    let server = serde_json::json!({"id": state.node_id, "host": state.public_host, "port": port_from_env()});
+    let mut nodes: Vec<serde_json::Value> = Vec::new();
+    if let Some(index) = &state.index { if let Ok(members) = index.list_nodes().await { nodes = members.into_iter().filter_map(|s| serde_json::from_str(&s).ok()).collect(); } }
+    axum::Json(serde_json::json!({"server": server, "nodes": nodes})).into_response()
+}
+
+/// How long a node stays listed without refreshing its key.
+pub const NODE_TTL_SECS: u64 = 30;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Keeps this node's index entry alive; other nodes treat it as dead once it expires.
+pub async fn heartbeat_task(state: AppState) {
+    let Some(index) = state.index.clone() else { return };
+    let id = state.node_id.clone();
+    let node = serde_json::json!({"id": id, "host": state.public_host, "port": port_from_env()}).to_string();
+    let mut ticker = tokio::time::interval(HEARTBEAT_INTERVAL);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = index.register_node(&id, &node, NODE_TTL_SECS).await { tracing::warn!(error = %e, "heartbeat failed"); }
+    }
+}
+
+/// Bound on the shutdown-time index cleanup so an unreachable index can't hold up exit.
+const DEREGISTER_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Removes this node's index entry so peers stop redirecting to it; its locations are left
+/// in place and are skipped by `download_file` once the node entry is gone.
+pub async fn deregister_self(state: &AppState) {
+    let Some(index) = &state.index else { return };
+    let id = &state.node_id;
+    match tokio::time::timeout(DEREGISTER_TIMEOUT, index.deregister_node(id)).await {
+        Ok(Ok(())) => tracing::info!(%id, "node deregistered"),
+        Ok(Err(e)) => tracing::warn!(%id, error = %e, "node deregistration failed"),
+        Err(_) => tracing::warn!(%id, "node deregistration timed out"),
+    }
+}
+
+/// The server's shutdown future: waits for `signal`, then takes this node out of the index.
+pub async fn graceful_shutdown(state: AppState, heartbeat: tokio::task::JoinHandle<()>, signal: impl std::future::Future<Output = ()>) {
+    signal.await;
+    // Stop the heartbeat first, or it would re-register the node while requests drain.
+    heartbeat.abort();
+    let _ = heartbeat.await;
+    deregister_self(&state).await;
+}
+
 pub async fn register_node_endpoint(State(state): State<AppState>, payload: Option<axum::Json<NodeRegisterReq>>) -> impl IntoResponse {
-    let id = payload.as_ref().and_then(|p| p.id.clone()).unwrap_or_else(|| format!("server-{}", std::process::id()));
+    let id = payload.as_ref().and_then(|p| p.id.clone()).unwrap_or_else(|| state.node_id.clone());
     let host = payload.as_ref().and_then(|p| p.host.clone()).unwrap_or_else(|| state.public_host.clone());
-    let port = payload.as_ref().and_then(|p| p.port).unwrap_or_else(|| port_from_env());
-    if let Some(url) = &state.redis_url { let node = serde_json::json!({"id": id, "host": host, "port": port}).to_string(); let _ = register_node(url, &node).await; }
+    let port = payload.as_ref().and_then(|p| p.port).unwrap_or_else(port_from_env);
+    if let Some(index) = &state.index { let node = serde_json::json!({"id": id, "host": host, "port": port}).to_string(); let _ = index.register_node(&id, &node, NODE_TTL_SECS).await; }
     axum::Json(serde_json::json!({"success": true})).into_response()
 }
 
-pub async fn list_nodes_endpoint(State(state): State<AppState>) -> impl IntoResponse {
-    if let Some(url) = &state.redis_url { if let Ok(members) = list_nodes(url).await { let nodes: Vec<serde_json::Value> = members.into_iter().filter_map(|s| serde_json::from_str(&s).ok()).collect(); return axum::Json(serde_json::json!({"nodes": nodes})).into_response(); } }
-    axum::Json(serde_json::json!({"nodes": []})).into_response()
-}
\ No newline at end of file
+#[derive(Deserialize)]
+pub struct NodesQuery {
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub offset: usize,
+}
+
+/// Live nodes, sorted by id so `offset` pages are stable.
+/// `limit` defaults to, and is capped at, `NODE_LIST_MAX`.
+pub async fn list_nodes_endpoint(State(state): State<AppState>, Query(q): Query<NodesQuery>) -> impl IntoResponse {
+    let mut nodes: Vec<serde_json::Value> = Vec::new();
+    if let Some(index) = &state.index { if let Ok(members) = index.list_nodes().await { nodes = members.into_iter().filter_map(|s| serde_json::from_str(&s).ok()).collect(); } }
+    // Store order is arbitrary; sort so `offset` pages are stable. Entries are per id, so there are no duplicates.
+    nodes.sort_by_cached_key(|n| n.get("id").and_then(|v| v.as_str()).map(str::to_string).unwrap_or_else(|| n.to_string()));
+    let total = nodes.len();
+    let limit = q.limit.unwrap_or(state.node_list_max).min(state.node_list_max);
+    let page: Vec<serde_json::Value> = nodes.into_iter().skip(q.offset).take(limit).collect();
+    with_cache_control(&state, axum::Json(serde_json::json!({"nodes": page, "total": total, "limit": limit, "offset": q.offset})).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+
+    use super::{graceful_shutdown, heartbeat_task, is_file_field};
+    use crate::index::{IndexStore, MemoryIndex, NodeStatus};
+    use crate::state::AppState;
+    use crate::testing::{Part, TempRoot, create_bucket, file_part, get, post_json, send, test_state, upload, visible_files};
+    use crate::util::percent_encode_key;
+
+    #[tokio::test]
+    async fn three_file_parts_store_three_files() {
+        let root = TempRoot::new();
+        let state = test_state(root.path());
+        create_bucket(&state, "docs").await;
+        let resp = upload(&state, "/api/buckets/docs/upload", &[file_part("a.txt", b"a"), file_part("b.txt", b"bb"), file_part("c.txt", b"ccc")]).await;
+        assert_eq!(resp.status, StatusCode::OK);
+        let body = resp.json();
+        let originals: Vec<&str> = body["files"].as_array().unwrap().iter().map(|f| f["originalName"].as_str().unwrap()).collect();
+        assert_eq!(originals, ["a.txt", "b.txt", "c.txt"]);
+        assert_eq!(visible_files(&root.path().join("docs")).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn failed_part_rolls_back_the_whole_upload() {
+        let root = TempRoot::new();
+        let mut state = test_state(root.path());
+        state.max_filename_bytes = 10;
+        create_bucket(&state, "docs").await;
+        let resp = upload(&state, "/api/buckets/docs/upload", &[file_part("a.txt", b"a"), file_part("b.txt", b"b"), file_part("far-too-long-name.txt", b"c")]).await;
+        assert_eq!(resp.status, StatusCode::BAD_REQUEST);
+        assert!(visible_files(&root.path().join("docs")).is_empty(), "earlier parts must be removed");
+        assert!(std::fs::read_dir(root.path().join("docs")).unwrap().next().is_none(), "no sidecars may be left behind");
+    }
+
+    #[test]
+    fn file_parts_are_named_file_or_unnamed_with_a_filename() {
+        assert!(is_file_field(Some("file"), Some("a.txt")));
+        assert!(is_file_field(Some("file"), None));
+        assert!(is_file_field(None, Some("a.txt")));
+        assert!(!is_file_field(None, None));
+        assert!(!is_file_field(Some("note"), Some("a.txt")));
+    }
+
+    #[tokio::test]
+    async fn mixed_named_and_unnamed_fields() {
+        let root = TempRoot::new();
+        let state = test_state(root.path());
+        create_bucket(&state, "docs").await;
+        let parts = [
+            file_part("named.txt", b"1"),
+            Part { name: None, filename: Some("unnamed.txt"), data: b"2" },
+            Part { name: Some("note"), filename: None, data: b"just a form value" },
+        ];
+        let resp = upload(&state, "/api/buckets/docs/upload", &parts).await;
+        assert_eq!(resp.status, StatusCode::OK);
+        let body = resp.json();
+        let originals: Vec<&str> = body["files"].as_array().unwrap().iter().map(|f| f["originalName"].as_str().unwrap()).collect();
+        assert_eq!(originals, ["named.txt", "unnamed.txt"]);
+    }
+
+    #[tokio::test]
+    async fn traversal_in_file_keys_is_rejected() {
+        let root = TempRoot::new();
+        let state = test_state(root.path());
+        create_bucket(&state, "docs").await;
+        std::fs::write(root.path().join("secret.txt"), b"top secret").unwrap();
+        for key in ["..%2fsecret.txt", "..%2F..%2Fsecret.txt", "%2e%2e%2fsecret.txt", "..%5csecret.txt", "%2Fsecret.txt", "sub%2F..%2F..%2Fsecret.txt"] {
+            for route in ["files", "info"] {
+                let resp = get(&state, &format!("/api/buckets/docs/{}/{}", route, key)).await;
+                assert_eq!(resp.status, StatusCode::BAD_REQUEST, "GET {} {}", route, key);
+            }
+            let resp = send(&state, Request::delete(format!("/api/buckets/docs/files/{}", key)).body(Body::empty()).unwrap()).await;
+            assert_eq!(resp.status, StatusCode::BAD_REQUEST, "DELETE {}", key);
+        }
+        assert_eq!(std::fs::read(root.path().join("secret.txt")).unwrap(), b"top secret");
+    }
+
+    #[tokio::test]
+    async fn traversal_in_bucket_names_is_rejected() {
+        let root = TempRoot::new();
+        let state = test_state(root.path());
+        for name in ["..", "../outside", "/tmp", "a\\b"] {
+            assert_eq!(post_json(&state, "/api/buckets", serde_json::json!({"name": name})).await.status, StatusCode::BAD_REQUEST, "{:?}", name);
+        }
+        let resp = send(&state, Request::delete("/api/buckets/..").body(Body::empty()).unwrap()).await;
+        assert_eq!(resp.status, StatusCode::BAD_REQUEST);
+        assert!(root.path().exists());
+    }
+
+    /// Uploads one file and returns the name it was stored under.
+    async fn upload_one(state: &AppState, bucket: &str, filename: &str, data: &[u8]) -> String {
+        let resp = upload(state, &format!("/api/buckets/{}/upload", bucket), &[file_part(filename, data)]).await;
+        assert_eq!(resp.status, StatusCode::OK, "{}", String::from_utf8_lossy(&resp.body));
+        resp.json()["files"][0]["name"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn download_content_type_follows_the_extension() {
+        let root = TempRoot::new();
+        let state = test_state(root.path());
+        create_bucket(&state, "docs").await;
+        for (filename, expected) in [("logo.png", "image/png"), ("report.pdf", "application/pdf"), ("data.unknownext", "application/octet-stream")] {
+            let stored = upload_one(&state, "docs", filename, b"not really that format").await;
+            let resp = get(&state, &format!("/api/buckets/docs/files/{}", percent_encode_key(&stored))).await;
+            assert_eq!(resp.status, StatusCode::OK);
+            assert_eq!(resp.header("content-type"), Some(expected), "{}", filename);
+        }
+    }
+
+    #[tokio::test]
+    async fn download_names_the_file_as_uploaded() {
+        let root = TempRoot::new();
+        let state = test_state(root.path());
+        create_bucket(&state, "docs").await;
+        let stored = upload_one(&state, "docs", "2024-06 report.txt", b"r").await;
+        let resp = get(&state, &format!("/api/buckets/docs/files/{}", percent_encode_key(&stored))).await;
+        assert_eq!(resp.header("content-disposition"), Some("attachment; filename=\"2024-06 report.txt\"; filename*=UTF-8''2024-06%20report.txt"));
+        let stored = upload_one(&state, "docs", "报告.txt", b"r").await;
+        let resp = get(&state, &format!("/api/buckets/docs/files/{}", percent_encode_key(&stored))).await;
+        assert_eq!(resp.header("content-disposition"), Some("attachment; filename=\"__.txt\"; filename*=UTF-8''%E6%8A%A5%E5%91%8A.txt"));
+    }
+
+    #[tokio::test]
+    async fn appended_log_keeps_its_dashed_name() {
+        let root = TempRoot::new();
+        let mut state = test_state(root.path());
+        state.clean_names = true;
+        create_bucket(&state, "logs").await;
+        let resp = send(&state, Request::post("/api/buckets/logs/append/2024-06-access.log").body(Body::from("line\n")).unwrap()).await;
+        assert_eq!(resp.status, StatusCode::OK, "{}", String::from_utf8_lossy(&resp.body));
+        let resp = get(&state, "/api/buckets/logs/files/2024-06-access.log").await;
+        assert_eq!(resp.status, StatusCode::OK);
+        assert_eq!(resp.body, b"line\n");
+        assert!(resp.header("content-disposition").unwrap().contains("filename=\"2024-06-access.log\""));
+    }
+
+    #[tokio::test]
+    async fn listing_pages_through_a_prefix() {
+        let root = TempRoot::new();
+        let state = test_state(root.path());
+        create_bucket(&state, "docs").await;
+        for name in ["report-1.txt", "report-2.txt", "report-3.txt", "notes-1.txt", "notes-2.txt"] { upload_one(&state, "docs", name, b"x").await; }
+        let first = get(&state, "/api/buckets/docs/files?prefix=report&limit=2").await.json();
+        assert_eq!((first["total"].as_u64(), first["hasMore"].as_bool(), first["files"].as_array().map(Vec::len)), (Some(3), Some(true), Some(2)));
+        let second = get(&state, "/api/buckets/docs/files?prefix=report&limit=2&offset=2").await.json();
+        assert_eq!((second["total"].as_u64(), second["hasMore"].as_bool(), second["files"].as_array().map(Vec::len)), (Some(3), Some(false), Some(1)));
+        let mut seen: Vec<String> = first["files"].as_array().unwrap().iter().chain(second["files"].as_array().unwrap()).map(|f| f["name"].as_str().unwrap().to_string()).collect();
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen.len(), 3);
+        assert!(seen.iter().all(|n| n.ends_with(".txt") && n.contains("report-")));
+        // Out-of-range values are clamped rather than rejected.
+        let clamped = get(&state, "/api/buckets/docs/files?limit=0&offset=-5").await.json();
+        assert_eq!((clamped["total"].as_u64(), clamped["files"].as_array().map(Vec::len)), (Some(5), Some(1)));
+        let past_end = get(&state, "/api/buckets/docs/files?offset=10").await.json();
+        assert_eq!((past_end["hasMore"].as_bool(), past_end["files"].as_array().map(Vec::len)), (Some(false), Some(0)));
+    }
+
+    #[tokio::test]
+    async fn shutdown_removes_the_node_from_the_index() {
+        let root = TempRoot::new();
+        let mut state = test_state(root.path());
+        let index = Arc::new(MemoryIndex::default());
+        state.index = Some(index.clone());
+        let id = state.node_id.clone();
+        let heartbeat = tokio::spawn(heartbeat_task(state.clone()));
+        // The first tick fires immediately.
+        for _ in 0..100 {
+            if index.node_status(&id).await.unwrap() == NodeStatus::Alive { break; }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(index.node_status(&id).await.unwrap(), NodeStatus::Alive, "heartbeat never registered the node");
+        graceful_shutdown(state, heartbeat, std::future::ready(())).await;
+        assert_eq!(index.node_status(&id).await.unwrap(), NodeStatus::Gone);
+        assert!(index.list_nodes().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn listings_report_rfc3339_times() {
+        let root = TempRoot::new();
+        let state = test_state(root.path());
+        create_bucket(&state, "docs").await;
+        upload_one(&state, "docs", "a.txt", b"a").await;
+        let listing = get(&state, "/api/buckets/docs/files").await.json();
+        let modified = listing["files"][0]["modified"].as_str().unwrap().to_string();
+        assert!(modified.ends_with('Z') && chrono::DateTime::parse_from_rfc3339(&modified).is_ok(), "{}", modified);
+    }
+
+    #[tokio::test]
+    async fn writes_over_quota_are_refused() {
+        let root = TempRoot::new();
+        let state = test_state(root.path());
+        assert_eq!(post_json(&state, "/api/buckets", serde_json::json!({"name": "small", "quotaBytes": 10})).await.status, StatusCode::OK);
+        create_bucket(&state, "big").await;
+        upload_one(&state, "small", "a.txt", b"123456").await;
+        let over = upload(&state, "/api/buckets/small/upload", &[file_part("b.txt", b"123456")]).await;
+        assert_eq!(over.status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!((over.json()["quotaBytes"].as_u64(), over.json()["usedBytes"].as_u64()), (Some(10), Some(6)));
+        assert_eq!(visible_files(&root.path().join("small")).len(), 1, "the refused upload must not be kept");
+        let append = |data: &'static str| Request::post("/api/buckets/small/append/log.txt").body(Body::from(data)).unwrap();
+        assert_eq!(send(&state, append("12345")).await.status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(send(&state, append("1234")).await.status, StatusCode::OK);
+        let stored = upload_one(&state, "big", "c.txt", b"1").await;
+        let moved = post_json(&state, &format!("/api/buckets/big/move/{}", percent_encode_key(&stored)), serde_json::json!({"targetBucket": "small"})).await;
+        assert_eq!(moved.status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn nested_keys_download_describe_and_delete() {
+        let root = TempRoot::new();
+        let mut state = test_state(root.path());
+        state.clean_names = true;
+        create_bucket(&state, "docs").await;
+        let stored = upload(&state, "/api/buckets/docs/upload?path=images/2024", &[file_part("logo.png", b"png")]).await.json()["files"][0]["name"].as_str().unwrap().to_string();
+        assert_eq!(stored, "images/2024/logo.png");
+        // A folder may share its name with an action, since actions have their own prefixes.
+        upload(&state, "/api/buckets/docs/upload?path=info", &[file_part("a.txt", b"a")]).await;
+
+        let resp = get(&state, "/api/buckets/docs/files/images/2024/logo.png").await;
+        assert_eq!((resp.status, resp.body.as_slice()), (StatusCode::OK, b"png".as_slice()));
+        assert_eq!(get(&state, "/api/buckets/docs/files/info/a.txt").await.body, b"a");
+        let info = get(&state, "/api/buckets/docs/info/images/2024/logo.png").await;
+        assert_eq!(info.status, StatusCode::OK);
+        assert_eq!(info.json()["size"].as_u64(), Some(3));
+        let listing = get(&state, "/api/buckets/docs/files?recursive=true").await.json();
+        let mut names: Vec<&str> = listing["files"].as_array().unwrap().iter().map(|f| f["name"].as_str().unwrap()).collect();
+        names.sort();
+        assert_eq!(names, ["images/2024/logo.png", "info/a.txt"]);
+
+        let deleted = send(&state, Request::delete("/api/buckets/docs/files/images/2024/logo.png").body(Body::empty()).unwrap()).await;
+        assert_eq!(deleted.status, StatusCode::OK);
+        assert_eq!(get(&state, "/api/buckets/docs/files/images/2024/logo.png").await.status, StatusCode::NOT_FOUND);
+        assert_eq!(get(&state, "/api/buckets/docs/files/info/a.txt").await.status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn readiness_reflects_the_storage() {
+        let root = TempRoot::new();
+        let mut state = test_state(root.path());
+        let ready = get(&state, "/health/status").await;
+        assert_eq!(ready.status, StatusCode::OK);
+        assert_eq!(ready.json()["storage"]["writable"], true);
+        // Running as root defeats permission bits, so make the root unwritable by making it a file.
+        let file_root = root.path().join("not-a-dir");
+        std::fs::write(&file_root, b"").unwrap();
+        state.root_dir = file_root;
+        let unavailable = get(&state, "/health/status").await;
+        assert_eq!(unavailable.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(unavailable.json()["failing"].as_array().unwrap().contains(&"writable".into()));
+        state.root_dir = root.path().to_path_buf();
+        state.min_free_bytes = u64::MAX;
+        let full = get(&state, "/health/status").await;
+        assert_eq!(full.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(full.json()["failing"], serde_json::json!(["freeSpace"]));
+        assert_eq!(get(&state, "/health").await.status, StatusCode::OK, "liveness doesn't depend on storage");
+    }
+
+    #[tokio::test]
+    async fn remote_files_are_only_refused_when_their_node_has_gone() {
+        let root = TempRoot::new();
+        let mut state = test_state(root.path());
+        let index = Arc::new(MemoryIndex::default());
+        state.index = Some(index.clone());
+        create_bucket(&state, "docs").await;
+        for (name, node) in [("legacy.txt", None), ("unknown.txt", Some("node.js-1")), ("alive.txt", Some("peer:3001")), ("gone.txt", Some("old:3001"))] {
+            let mut value = serde_json::json!({"host": "peer", "port": 3001});
+            if let Some(node) = node { value["id"] = node.into(); }
+            index.set_location("docs", name, &value.to_string()).await.unwrap();
+        }
+        index.register_node("peer:3001", "{}", 30).await.unwrap();
+        index.register_node("old:3001", "{}", 30).await.unwrap();
+        index.deregister_node("old:3001").await.unwrap();
+        for name in ["legacy.txt", "unknown.txt", "alive.txt"] {
+            let resp = get(&state, &format!("/api/buckets/docs/files/{}", name)).await;
+            assert_eq!(resp.status, StatusCode::SEE_OTHER, "{}", name);
+            assert_eq!(resp.header("location"), Some(format!("http://peer:3001/api/buckets/docs/files/{}", name).as_str()));
+        }
+        assert_eq!(get(&state, "/api/buckets/docs/files/gone.txt").await.status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn shutting_down_one_replica_leaves_the_others_registered() {
+        let root = TempRoot::new();
+        let index = Arc::new(MemoryIndex::default());
+        let replica = |id: &str| { let mut state = test_state(root.path()); state.index = Some(index.clone()); state.node_id = id.to_string(); state };
+        let (first, second) = (replica("replica-a:3001"), replica("replica-b:3001"));
+        let heartbeats = [tokio::spawn(heartbeat_task(first.clone())), tokio::spawn(heartbeat_task(second.clone()))];
+        for _ in 0..100 {
+            if index.list_nodes().await.unwrap().len() == 2 { break; }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let [a, b] = heartbeats;
+        graceful_shutdown(first, a, std::future::ready(())).await;
+        assert_eq!(index.node_status("replica-a:3001").await.unwrap(), NodeStatus::Gone);
+        assert_eq!(index.node_status("replica-b:3001").await.unwrap(), NodeStatus::Alive);
+        assert_eq!(index.list_nodes().await.unwrap().len(), 1);
+        b.abort();
+    }
+
+    #[tokio::test]
+    async fn rotation_needs_room_in_the_quota() {
+        let root = TempRoot::new();
+        let mut state = test_state(root.path());
+        state.log_rotate_bytes = Some(4);
+        assert_eq!(post_json(&state, "/api/buckets", serde_json::json!({"name": "logs", "quotaBytes": 10})).await.status, StatusCode::OK);
+        let append = |data: &'static str| Request::post("/api/buckets/logs/append/app.log").body(Body::from(data)).unwrap();
+        assert_eq!(send(&state, append("123456")).await.status, StatusCode::OK);
+        assert_eq!(send(&state, append("7")).await.status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(visible_files(&root.path().join("logs")), ["app.log"]);
+        assert_eq!(get(&state, "/api/buckets/logs/files/app.log").await.body, b"123456");
+    }
+
+    #[tokio::test]
+    async fn appends_only_create_validly_named_buckets() {
+        let root = TempRoot::new();
+        let mut state = test_state(root.path());
+        state.s3_compat_names = true;
+        let append = |bucket: &str| Request::post(format!("/api/buckets/{}/append/log.txt", bucket)).body(Body::from("line\n")).unwrap();
+        assert_eq!(send(&state, append("ab")).await.status, StatusCode::BAD_REQUEST);
+        assert!(!root.path().join("ab").exists());
+        assert_eq!(send(&state, append("app-logs")).await.status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn concat_output_is_stored_like_an_upload() {
+        let root = TempRoot::new();
+        let mut state = test_state(root.path());
+        state.clean_names = true;
+        assert_eq!(post_json(&state, "/api/buckets", serde_json::json!({"name": "docs", "defaultMetadata": {"team": "ops"}, "quotaBytes": 20})).await.status, StatusCode::OK);
+        upload(&state, "/api/buckets/docs/upload?path=logs", &[file_part("a.txt", b"aaaa\n"), file_part("b.txt", b"bbbb\n")]).await;
+        let concat = || post_json(&state, "/api/buckets/docs/concat", serde_json::json!({"filenames": ["logs/a.txt", "logs/b.txt"], "outputName": "out/all.txt"}));
+        let resp = concat().await;
+        assert_eq!(resp.status, StatusCode::OK, "{}", String::from_utf8_lossy(&resp.body));
+        let file = &resp.json()["file"];
+        assert_eq!((&file["name"], &file["size"], &file["metadata"]["team"]), (&serde_json::json!("out/all.txt"), &serde_json::json!(10), &serde_json::json!("ops")));
+        assert_eq!(get(&state, "/api/buckets/docs/files/out/all.txt").await.body, b"aaaa\nbbbb\n");
+        assert_eq!(get(&state, "/api/buckets/docs/files/out/all.txt/verify").await.json()["valid"], true);
+        assert_eq!(concat().await.status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn nested_logs_append_and_rotate() {
+        let root = TempRoot::new();
+        let mut state = test_state(root.path());
+        state.log_rotate_bytes = Some(4);
+        create_bucket(&state, "logs").await;
+        for line in ["one\n", "two\n"] {
+            let resp = send(&state, Request::post("/api/buckets/logs/files/2024/06/app.log/append").body(Body::from(line)).unwrap()).await;
+            assert_eq!(resp.status, StatusCode::OK, "{}", String::from_utf8_lossy(&resp.body));
+        }
+        assert_eq!(get(&state, "/api/buckets/logs/files/2024/06/app.log").await.body, b"two\n");
+        let segments = get(&state, "/api/buckets/logs/files/2024/06/app.log/segments").await.json();
+        assert_eq!(segments["segments"][0]["name"], "2024/06/app.log.1.gz");
+        assert_eq!(get(&state, "/api/buckets/logs/files/2024/06/app.log.1.gz").await.status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn bucket_totals_include_nested_files() {
+        let root = TempRoot::new();
+        let state = test_state(root.path());
+        assert_eq!(post_json(&state, "/api/buckets", serde_json::json!({"name": "docs", "quotaBytes": 10})).await.status, StatusCode::OK);
+        upload_one(&state, "docs", "a.txt", b"123").await;
+        upload(&state, "/api/buckets/docs/upload?path=deep/er", &[file_part("b.txt", b"4567")]).await;
+        let listing = get(&state, "/api/buckets").await.json();
+        let docs = &listing["buckets"][0];
+        assert_eq!((docs["fileCount"].as_u64(), docs["size"].as_u64(), docs["usedBytes"].as_u64()), (Some(2), Some(7), Some(7)));
+        let over = upload(&state, "/api/buckets/docs/upload?path=deep", &[file_part("c.txt", b"8901")]).await;
+        assert_eq!(over.status, StatusCode::PAYLOAD_TOO_LARGE, "nested bytes count against the quota");
+    }
+}
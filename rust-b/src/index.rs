@@ -0,0 +1,139 @@
+//! The cluster index: where each stored file lives and which nodes are alive.
+//! `INDEX_BACKEND` picks the implementation; Redis is the default.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use dashmap::{DashMap, DashSet};
+
+use crate::redis::{RedisPool, del_key, exists, get_key, mget, scan_keys, set_ex, set_key};
+
+/// Location values and node entries are JSON strings, as written by `register_location`
+/// and the heartbeat.
+#[async_trait]
+pub trait IndexStore: Send + Sync {
+    async fn set_location(&self, bucket: &str, stored: &str, value: &str) -> anyhow::Result<()>;
+    async fn get_location(&self, bucket: &str, stored: &str) -> anyhow::Result<Option<String>>;
+    async fn del_location(&self, bucket: &str, stored: &str) -> anyhow::Result<()>;
+    /// Registers (or refreshes) a node; it drops out of `list_nodes` unless refreshed within `ttl_secs`.
+    async fn register_node(&self, id: &str, node: &str, ttl_secs: u64) -> anyhow::Result<()>;
+    /// Drops a node immediately instead of waiting for it to expire.
+    async fn deregister_node(&self, id: &str) -> anyhow::Result<()>;
+    async fn node_status(&self, id: &str) -> anyhow::Result<NodeStatus>;
+    /// JSON of every live node.
+    async fn list_nodes(&self) -> anyhow::Result<Vec<String>>;
+}
+
+/// What the index knows about a node a location entry names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeStatus {
+    Alive,
+    /// It has sent heartbeats, but its key has expired or it deregistered.
+    Gone,
+    /// It never sent a heartbeat: entries written before heartbeats existed, or by nodes
+    /// that don't send them (such as the Node.js server).
+    Unknown,
+}
+
+/// Builds the store named by `INDEX_BACKEND` (`redis` or `memory`).
+/// `None` when the Redis backend is selected but Redis isn't configured.
+pub fn build_index(backend: &str, redis: Option<&RedisPool>) -> anyhow::Result<Option<Arc<dyn IndexStore>>> {
+    match backend {
+        "" | "redis" => Ok(redis.map(|pool| Arc::new(RedisIndex(pool.clone())) as Arc<dyn IndexStore>)),
+        "memory" => Ok(Some(Arc::new(MemoryIndex::default()))),
+        other => anyhow::bail!("不支持的 INDEX_BACKEND: {}（可选 redis、memory）", other),
+    }
+}
+
+/// `{bucket}:{stored}` location keys, expiring `node:<id>` keys, and a `node-seen:<id>` key
+/// per node that ever sent a heartbeat, so an expired node can be told from an unknown one.
+pub struct RedisIndex(pub RedisPool);
+
+fn location_key(bucket: &str, stored: &str) -> String { format!("{}:{}", bucket, stored) }
+
+fn node_key(id: &str) -> String { format!("node:{}", id) }
+
+fn seen_key(id: &str) -> String { format!("node-seen:{}", id) }
+
+#[async_trait]
+impl IndexStore for RedisIndex {
+    async fn set_location(&self, bucket: &str, stored: &str, value: &str) -> anyhow::Result<()> {
+        set_key(&self.0, &location_key(bucket, stored), value).await
+    }
+
+    async fn get_location(&self, bucket: &str, stored: &str) -> anyhow::Result<Option<String>> {
+        get_key(&self.0, &location_key(bucket, stored)).await
+    }
+
+    async fn del_location(&self, bucket: &str, stored: &str) -> anyhow::Result<()> {
+        del_key(&self.0, &location_key(bucket, stored)).await
+    }
+
+    async fn register_node(&self, id: &str, node: &str, ttl_secs: u64) -> anyhow::Result<()> {
+        set_key(&self.0, &seen_key(id), "1").await?;
+        set_ex(&self.0, &node_key(id), node, ttl_secs).await
+    }
+
+    async fn deregister_node(&self, id: &str) -> anyhow::Result<()> {
+        del_key(&self.0, &node_key(id)).await
+    }
+
+    async fn node_status(&self, id: &str) -> anyhow::Result<NodeStatus> {
+        if exists(&self.0, &node_key(id)).await? { return Ok(NodeStatus::Alive); }
+        Ok(if exists(&self.0, &seen_key(id)).await? { NodeStatus::Gone } else { NodeStatus::Unknown })
+    }
+
+    async fn list_nodes(&self) -> anyhow::Result<Vec<String>> {
+        // A key can expire between SCAN and MGET; `mget` skips those.
+        mget(&self.0, &scan_keys(&self.0, &node_key("*")).await?).await
+    }
+}
+
+/// Process-local index for single-node deployments and tests; nothing is shared between nodes.
+#[derive(Default)]
+pub struct MemoryIndex {
+    locations: DashMap<String, String>,
+    /// Node JSON and the instant it expires.
+    nodes: DashMap<String, (String, Instant)>,
+    /// Every node that ever registered.
+    seen: DashSet<String>,
+}
+
+#[async_trait]
+impl IndexStore for MemoryIndex {
+    async fn set_location(&self, bucket: &str, stored: &str, value: &str) -> anyhow::Result<()> {
+        self.locations.insert(location_key(bucket, stored), value.to_string());
+        Ok(())
+    }
+
+    async fn get_location(&self, bucket: &str, stored: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.locations.get(&location_key(bucket, stored)).map(|v| v.clone()))
+    }
+
+    async fn del_location(&self, bucket: &str, stored: &str) -> anyhow::Result<()> {
+        self.locations.remove(&location_key(bucket, stored));
+        Ok(())
+    }
+
+    async fn register_node(&self, id: &str, node: &str, ttl_secs: u64) -> anyhow::Result<()> {
+        self.seen.insert(id.to_string());
+        self.nodes.insert(id.to_string(), (node.to_string(), Instant::now() + Duration::from_secs(ttl_secs)));
+        Ok(())
+    }
+
+    async fn deregister_node(&self, id: &str) -> anyhow::Result<()> {
+        self.nodes.remove(id);
+        Ok(())
+    }
+
+    async fn node_status(&self, id: &str) -> anyhow::Result<NodeStatus> {
+        if self.nodes.get(id).is_some_and(|n| n.1 > Instant::now()) { return Ok(NodeStatus::Alive); }
+        Ok(if self.seen.contains(id) { NodeStatus::Gone } else { NodeStatus::Unknown })
+    }
+
+    async fn list_nodes(&self) -> anyhow::Result<Vec<String>> {
+        let now = Instant::now();
+        self.nodes.retain(|_, n| n.1 > now);
+        Ok(self.nodes.iter().map(|n| n.0.clone()).collect())
+    }
+}
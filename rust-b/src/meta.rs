@@ -0,0 +1,95 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use axum::http::HeaderMap;
+use serde::{Deserialize, Serialize};
+
+use crate::util::{is_plain_name, join_key, original_key, split_key};
+
+pub type Metadata = BTreeMap<String, String>;
+
+const BUCKET_CONFIG: &str = ".bucket.json";
+const META_HEADER_PREFIX: &str = "x-meta-";
+/// Metadata key holding the content type resolved for an upload.
+pub const CONTENT_TYPE_KEY: &str = "content-type";
+
+/// Per-bucket settings kept in `.bucket.json` inside the bucket directory.
+#[derive(Serialize, Deserialize, Default)]
+pub struct BucketConfig {
+    #[serde(rename = "defaultMetadata", default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub default_metadata: Metadata,
+    /// Uploads that would take the bucket past this many bytes are refused.
+    #[serde(rename = "quotaBytes", default, skip_serializing_if = "Option::is_none")]
+    pub quota_bytes: Option<u64>,
+}
+
+pub fn load_bucket_config(bucket_dir: &Path) -> BucketConfig {
+    std::fs::read(bucket_dir.join(BUCKET_CONFIG)).ok()
+        .and_then(|b| serde_json::from_slice(&b).ok())
+        .unwrap_or_default()
+}
+
+pub fn has_bucket_config(bucket_dir: &Path) -> bool {
+    bucket_dir.join(BUCKET_CONFIG).exists()
+}
+
+pub fn save_bucket_config(bucket_dir: &Path, config: &BucketConfig) -> anyhow::Result<()> {
+    std::fs::write(bucket_dir.join(BUCKET_CONFIG), serde_json::to_vec_pretty(config)?)?;
+    Ok(())
+}
+
+fn file_meta_path(bucket_dir: &Path, stored: &str) -> PathBuf { crate::util::sidecar_path(bucket_dir, stored, ".meta.json") }
+
+pub fn load_file_metadata(bucket_dir: &Path, stored: &str) -> Option<Metadata> {
+    std::fs::read(file_meta_path(bucket_dir, stored)).ok().and_then(|b| serde_json::from_slice(&b).ok())
+}
+
+pub async fn save_file_metadata(bucket_dir: &Path, stored: &str, meta: &Metadata) -> anyhow::Result<()> {
+    tokio::fs::write(file_meta_path(bucket_dir, stored), serde_json::to_vec(meta)?).await?;
+    Ok(())
+}
+
+pub fn remove_file_metadata(bucket_dir: &Path, stored: &str) {
+    let _ = std::fs::remove_file(file_meta_path(bucket_dir, stored));
+}
+
+fn original_name_path(bucket_dir: &Path, stored: &str) -> PathBuf { crate::util::sidecar_path(bucket_dir, stored, ".name") }
+
+/// The key `stored` was written under, read from the name recorded beside it. Files
+/// written before names were recorded fall back to dropping the `timestamp-rand-` prefix.
+pub fn original_key_of(bucket_dir: &Path, stored: &str) -> String {
+    let recorded = std::fs::read_to_string(original_name_path(bucket_dir, stored)).ok().filter(|n| is_plain_name(n));
+    match recorded {
+        Some(name) => join_key(split_key(stored).0, &name),
+        None => original_key(stored),
+    }
+}
+
+/// Records the key a file was written under; only its last segment is kept, since the
+/// folder is the stored key's own.
+pub async fn save_original_name(bucket_dir: &Path, stored: &str, original: &str) -> anyhow::Result<()> {
+    tokio::fs::write(original_name_path(bucket_dir, stored), split_key(original).1).await?;
+    Ok(())
+}
+
+pub fn remove_original_name(bucket_dir: &Path, stored: &str) {
+    let _ = std::fs::remove_file(original_name_path(bucket_dir, stored));
+}
+
+/// Collects `x-meta-<key>` request headers as `<key>` entries.
+pub fn metadata_from_headers(headers: &HeaderMap) -> Metadata {
+    headers.iter()
+        .filter_map(|(k, v)| {
+            let key = k.as_str().strip_prefix(META_HEADER_PREFIX)?;
+            if key.is_empty() { return None; }
+            Some((key.to_string(), v.to_str().ok()?.to_string()))
+        })
+        .collect()
+}
+
+/// Bucket defaults overlaid with the request's own values.
+pub fn merge_metadata(defaults: &Metadata, overrides: Metadata) -> Metadata {
+    let mut merged = defaults.clone();
+    merged.extend(overrides);
+    merged
+}
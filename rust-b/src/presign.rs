@@ -0,0 +1,141 @@
+use axum::{extract::{Path as AxPath, Query, State}, http::{HeaderMap, StatusCode}, response::IntoResponse};
+use hmac::Mac;
+use serde::Deserialize;
+
+use crate::handlers::{DownloadQuery, download_file, file_exists, node_gone, node_unavailable, remote_node, resolve_stored_name};
+use crate::proxy::{PROXIED_HEADER, proxy_download};
+use crate::receipt::mac;
+use crate::state::AppState;
+use crate::util::{percent_encode_key, percent_encode_segment};
+
+const DEFAULT_EXPIRY_SECS: u64 = 3600;
+/// Links stay shareable for at most a week, as with S3.
+const MAX_EXPIRY_SECS: u64 = 7 * 86400;
+
+/// `bucket|filename|expires`, over the names as the client addresses them.
+fn signing_input(bucket: &str, filename: &str, expires: i64) -> String {
+    format!("{}|{}|{}", bucket, filename, expires)
+}
+
+#[derive(Deserialize)]
+pub struct PresignReq { #[serde(rename = "expiresInSecs")] pub expires_in_secs: Option<u64> }
+
+/// Issues a time-limited link to `GET /api/public/:bucket/*path` that needs no API key.
+pub async fn presign_url(State(state): State<AppState>, AxPath((bucket, filename)): AxPath<(String, String)>, payload: Option<axum::Json<PresignReq>>) -> impl IntoResponse {
+    let Some(secret) = &state.signing_secret else { return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"未配置签名密钥"}))).into_response() };
+    let expires_in = payload.and_then(|p| p.0.expires_in_secs).unwrap_or(DEFAULT_EXPIRY_SECS);
+    if expires_in == 0 || expires_in > MAX_EXPIRY_SECS { return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"有效期不合法","max": MAX_EXPIRY_SECS}))).into_response(); }
+    if state.resolve_file_path(&bucket, &filename).is_none() { return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"文件名不合法"}))).into_response(); }
+    if !file_exists(&state, &bucket, &filename).await { return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"文件不存在"}))).into_response(); }
+    let expires = chrono::Utc::now().timestamp() + expires_in as i64;
+    let sig = hex::encode(mac(secret, &signing_input(&bucket, &filename, expires)).finalize().into_bytes());
+    let url = format!("/api/public/{}/{}?expires={}&sig={}", percent_encode_segment(&bucket), percent_encode_key(&filename), expires, sig);
+    let expires_at = chrono::DateTime::from_timestamp(expires, 0).map(|t| t.to_rfc3339_opts(chrono::SecondsFormat::Secs, true));
+    axum::Json(serde_json::json!({"url": url, "expires": expires, "expiresAt": expires_at})).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct PublicQuery { pub expires: Option<i64>, pub sig: Option<String>, pub disposition: Option<String> }
+
+/// Serves a presigned link: `403` unless the signature matches, `410` once it has expired.
+/// The signature is checked first, so an edited `expires` is reported as tampering.
+pub async fn public_download(State(state): State<AppState>, AxPath((bucket, filename)): AxPath<(String, String)>, Query(q): Query<PublicQuery>, headers: HeaderMap) -> impl IntoResponse {
+    let Some(secret) = &state.signing_secret else { return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"未配置签名密钥"}))).into_response() };
+    let (Some(expires), Some(sig)) = (q.expires, q.sig.as_deref().and_then(|s| hex::decode(s).ok())) else { return (StatusCode::FORBIDDEN, axum::Json(serde_json::json!({"error":"链接签名无效"}))).into_response() };
+    // `verify_slice` compares in constant time.
+    if mac(secret, &signing_input(&bucket, &filename, expires)).verify_slice(&sig).is_err() { return (StatusCode::FORBIDDEN, axum::Json(serde_json::json!({"error":"链接签名无效"}))).into_response(); }
+    if chrono::Utc::now().timestamp() > expires { return (StatusCode::GONE, axum::Json(serde_json::json!({"error":"链接已过期"}))).into_response(); }
+    // Another node's copy is fetched through that node's public route with the same signature;
+    // the route its location points at wants an API key the client doesn't have.
+    if let Some(stored) = resolve_stored_name(&state, &bucket, &filename).await {
+        let local = state.resolve_file_path(&bucket, &stored).is_some_and(|p| p.is_file()) || state.resolve_bucket_path(&bucket).is_some_and(|dir| crate::cold::is_present(&dir, &stored));
+        if let (false, Some((node, origin))) = (local, remote_node(&state, &bucket, &stored).await) {
+            if let Some(id) = &node { if node_gone(&state, id).await { return node_unavailable(id); } }
+            let mut target = format!("{}/api/public/{}/{}?expires={}&sig={}", origin, percent_encode_segment(&bucket), percent_encode_key(&filename), expires, hex::encode(&sig));
+            if let Some(d) = &q.disposition { target.push_str(&format!("&disposition={}", percent_encode_segment(d))); }
+            if state.proxy_mode && !headers.contains_key(PROXIED_HEADER) { return proxy_download(&state, &target, &headers, None).await; }
+            return axum::response::Redirect::to(&target).into_response();
+        }
+    }
+    download_file(State(state), AxPath((bucket, filename)), Query(DownloadQuery { disposition: q.disposition }), None, headers).await.into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use hmac::Mac;
+
+    use super::signing_input;
+    use crate::index::{IndexStore, MemoryIndex};
+    use crate::receipt::mac;
+    use crate::state::AppState;
+    use crate::testing::{TempRoot, create_bucket, file_part, get, post_json, test_state, upload};
+
+    async fn signed_state(root: &TempRoot) -> AppState {
+        let mut state = test_state(root.path());
+        state.signing_secret = Some("secret".to_string());
+        state.clean_names = true;
+        create_bucket(&state, "docs").await;
+        upload(&state, "/api/buckets/docs/upload", &[file_part("a.txt", b"hello")]).await;
+        state
+    }
+
+    #[tokio::test]
+    async fn presigned_url_serves_the_file() {
+        let root = TempRoot::new();
+        let state = signed_state(&root).await;
+        let presigned = post_json(&state, "/api/buckets/docs/presign/a.txt", serde_json::json!({"expiresInSecs": 60})).await;
+        assert_eq!(presigned.status, StatusCode::OK);
+        let resp = get(&state, presigned.json()["url"].as_str().unwrap()).await;
+        assert_eq!(resp.status, StatusCode::OK);
+        assert_eq!(resp.body, b"hello");
+    }
+
+    #[tokio::test]
+    async fn tampered_urls_are_forbidden() {
+        let root = TempRoot::new();
+        let state = signed_state(&root).await;
+        upload(&state, "/api/buckets/docs/upload", &[file_part("b.txt", b"other")]).await;
+        let url = post_json(&state, "/api/buckets/docs/presign/a.txt", serde_json::json!({})).await.json()["url"].as_str().unwrap().to_string();
+        let (path, query) = url.split_once('?').unwrap();
+        let expires: i64 = query.split('&').find_map(|p| p.strip_prefix("expires=")).unwrap().parse().unwrap();
+        let sig = query.split('&').find_map(|p| p.strip_prefix("sig=")).unwrap();
+        let flipped = format!("{}{}", if sig.starts_with('0') { '1' } else { '0' }, &sig[1..]);
+        for tampered in [
+            format!("{}?expires={}&sig={}", path, expires, flipped),
+            format!("{}?expires={}&sig={}", path, expires + 3600, sig),
+            format!("{}?{}", path.replace("a.txt", "b.txt"), query),
+            format!("{}?expires={}", path, expires),
+        ] {
+            assert_eq!(get(&state, &tampered).await.status, StatusCode::FORBIDDEN, "{}", tampered);
+        }
+    }
+
+    #[tokio::test]
+    async fn expired_urls_are_gone() {
+        let root = TempRoot::new();
+        let state = signed_state(&root).await;
+        let expires = chrono::Utc::now().timestamp() - 1;
+        let sig = hex::encode(mac("secret", &signing_input("docs", "a.txt", expires)).finalize().into_bytes());
+        let resp = get(&state, &format!("/api/public/docs/a.txt?expires={}&sig={}", expires, sig)).await;
+        assert_eq!(resp.status, StatusCode::GONE);
+    }
+
+    #[tokio::test]
+    async fn remote_files_redirect_to_the_peers_public_link() {
+        let root = TempRoot::new();
+        let mut state = test_state(root.path());
+        state.signing_secret = Some("secret".to_string());
+        let index = std::sync::Arc::new(MemoryIndex::default());
+        state.index = Some(index.clone());
+        create_bucket(&state, "docs").await;
+        index.set_location("docs", "remote.txt", r#"{"host":"peer","port":3001,"id":"peer:3001"}"#).await.unwrap();
+        let url = post_json(&state, "/api/buckets/docs/presign/remote.txt", serde_json::json!({})).await.json()["url"].as_str().unwrap().to_string();
+        let resp = get(&state, &url).await;
+        assert_eq!(resp.status, StatusCode::SEE_OTHER);
+        assert_eq!(resp.header("location"), Some(format!("http://peer:3001{}", url).as_str()));
+        index.register_node("peer:3001", "{}", 30).await.unwrap();
+        index.deregister_node("peer:3001").await.unwrap();
+        assert_eq!(get(&state, &url).await.status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
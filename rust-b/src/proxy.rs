@@ -0,0 +1,38 @@
+use axum::{body::Body, http::{HeaderMap, StatusCode, header}, response::{IntoResponse, Response}};
+
+use crate::state::AppState;
+use crate::trace::TraceContext;
+
+/// Set on proxied requests so the remote node answers from its own disk instead of
+/// proxying again (two nodes pointing at each other would otherwise loop).
+pub const PROXIED_HEADER: &str = "x-fileio-proxied";
+
+/// Request headers passed through to the owning node so ranges and revalidation work end to end.
+const FORWARDED_REQUEST: &[header::HeaderName] = &[
+    header::RANGE, header::IF_RANGE, header::IF_NONE_MATCH, header::IF_MODIFIED_SINCE, header::IF_MATCH, header::IF_UNMODIFIED_SINCE,
+];
+
+/// Response headers relayed back to the client.
+const FORWARDED_RESPONSE: &[header::HeaderName] = &[
+    header::CONTENT_TYPE, header::CONTENT_LENGTH, header::CONTENT_RANGE, header::ACCEPT_RANGES, header::CONTENT_DISPOSITION,
+    header::ETAG, header::LAST_MODIFIED, header::X_CONTENT_TYPE_OPTIONS,
+];
+
+/// Streams a download from the node that owns the file instead of redirecting the client there.
+pub async fn proxy_download(state: &AppState, target: &str, headers: &HeaderMap, trace: Option<&TraceContext>) -> Response {
+    let mut req = state.http.get(target).header(PROXIED_HEADER, "1");
+    for name in FORWARDED_REQUEST.iter().chain(std::iter::once(&header::HeaderName::from_static("x-api-key"))) {
+        for value in headers.get_all(name) { req = req.header(name, value); }
+    }
+    if let Some(ctx) = trace { req = req.headers(ctx.outbound_headers()); }
+    let upstream = match req.send().await {
+        Ok(r) => r,
+        Err(e) => return (StatusCode::BAD_GATEWAY, axum::Json(serde_json::json!({"error":"无法从远程节点获取文件","details":e.to_string()}))).into_response(),
+    };
+    let status = StatusCode::from_u16(upstream.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let mut resp_headers = HeaderMap::new();
+    for name in FORWARDED_RESPONSE {
+        if let Some(v) = upstream.headers().get(name) { resp_headers.insert(name, v.clone()); }
+    }
+    (status, resp_headers, Body::from_stream(upstream.bytes_stream())).into_response()
+}
@@ -0,0 +1,84 @@
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use axum::{extract::{Query, State}, http::StatusCode, response::IntoResponse};
+use serde::Deserialize;
+
+use crate::cache::bump_generation;
+use crate::cold::stored_from_marker;
+use crate::state::AppState;
+use crate::util::is_hidden;
+
+/// Marks a bucket as being written to for as long as it lives, so `prune_empty`
+/// won't remove the directory out from under an upload.
+pub struct BucketWriteGuard { writers: std::sync::Arc<dashmap::DashMap<String, usize>>, bucket: String }
+
+impl BucketWriteGuard {
+    /// Take this before creating the bucket directory or any file in it.
+    pub fn enter(state: &AppState, bucket: &str) -> Self {
+        *state.bucket_writers.entry(bucket.to_string()).or_insert(0) += 1;
+        BucketWriteGuard { writers: state.bucket_writers.clone(), bucket: bucket.to_string() }
+    }
+}
+
+impl Drop for BucketWriteGuard {
+    fn drop(&mut self) {
+        self.writers.remove_if_mut(&self.bucket, |_, n| { *n -= 1; *n == 0 });
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PruneQuery {
+    #[serde(rename = "minAgeSecs")]
+    pub min_age_secs: Option<u64>,
+    #[serde(rename = "dryRun", default)]
+    pub dry_run: bool,
+}
+
+/// A bucket holding nothing but sidecars (config, stray metadata) counts as empty.
+/// Cold-tier markers stand for real files, so they keep a bucket alive.
+fn is_empty_bucket(dir: &Path) -> bool {
+    let Ok(iter) = fs::read_dir(dir) else { return false };
+    iter.filter_map(Result::ok).all(|e| {
+        let name = e.file_name().to_string_lossy().to_string();
+        is_hidden(&name) && stored_from_marker(&name).is_none() && e.file_type().is_ok_and(|t| t.is_file())
+    })
+}
+
+fn older_than(dir: &Path, min_age: Duration) -> bool {
+    fs::metadata(dir).and_then(|m| m.modified()).ok()
+        .and_then(|t| SystemTime::now().duration_since(t).ok())
+        .is_some_and(|age| age >= min_age)
+}
+
+/// Removes empty bucket directories under `ROOT_DIR` that haven't changed for `minAgeSecs`
+/// (default `PRUNE_MIN_AGE_SECS`). `PROTECTED_BUCKETS`, the quarantine bucket and
+/// `BUCKET_PATHS` buckets are never pruned, nor is any bucket with a write in progress.
+pub async fn prune_empty(State(state): State<AppState>, Query(q): Query<PruneQuery>) -> impl IntoResponse {
+    let min_age = q.min_age_secs.map(Duration::from_secs).unwrap_or(state.prune_min_age);
+    let worker = state.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let state = worker;
+        let mut pruned = Vec::new();
+        for entry in fs::read_dir(&state.root_dir)?.filter_map(Result::ok) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let dir = entry.path();
+            if !dir.is_dir() || state.protected_buckets.contains(&name) || state.bucket_paths.contains_key(&name) || state.quarantine_bucket.as_deref() == Some(name.as_str()) { continue; }
+            // Holding the entry blocks `BucketWriteGuard::enter` for this bucket until the
+            // directory is gone, so a new upload recreates it instead of racing the removal.
+            let writers = state.bucket_writers.entry(name.clone()).or_insert(0);
+            if *writers > 0 || !older_than(&dir, min_age) || !is_empty_bucket(&dir) { continue; }
+            if q.dry_run || fs::remove_dir_all(&dir).is_ok() { pruned.push(name.clone()); }
+            drop(writers);
+            state.bucket_writers.remove_if(&name, |_, n| *n == 0);
+        }
+        Ok::<_, std::io::Error>(pruned)
+    }).await;
+    match result {
+        Ok(Ok(pruned)) => {
+            if !q.dry_run { for name in &pruned { bump_generation(&state, name); } }
+            axum::Json(serde_json::json!({"success": true, "dryRun": q.dry_run, "pruned": pruned})).into_response()
+        }
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({"error":"无法读取储存桶目录"}))).into_response(),
+    }
+}
@@ -0,0 +1,93 @@
+use std::fs;
+use std::path::Path;
+use axum::{extract::{Path as AxPath, State}, http::StatusCode, response::IntoResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::state::AppState;
+use crate::util::format_time;
+
+/// Sidecar stored next to every quarantined file.
+#[derive(Serialize, Deserialize)]
+pub struct QuarantineRecord {
+    pub bucket: String,
+    #[serde(rename = "originalName")]
+    pub original_name: String,
+    pub reason: String,
+    #[serde(rename = "rejectedAt")]
+    pub rejected_at: String,
+}
+
+fn record_path(dir: &Path, name: &str) -> std::path::PathBuf { dir.join(format!(".{}.json", name)) }
+
+/// Moves a rejected upload into `QUARANTINE_BUCKET` instead of deleting it.
+/// Returns `false` (and leaves the file alone) when no quarantine bucket is configured,
+/// so callers fall back to removing the file themselves.
+pub async fn quarantine_upload(state: &AppState, bucket: &str, path: &Path, original_name: &str, reason: &str) -> bool {
+    let Some(qbucket) = &state.quarantine_bucket else { return false };
+    let Some(stored) = path.file_name().map(|n| n.to_string_lossy().to_string()) else { return false };
+    let Some(qdir) = state.resolve_bucket_path(qbucket) else { return false };
+    if tokio::fs::create_dir_all(&qdir).await.is_err() { return false; }
+    if tokio::fs::rename(path, qdir.join(&stored)).await.is_err() { return false; }
+    let record = QuarantineRecord { bucket: bucket.to_string(), original_name: original_name.to_string(), reason: reason.to_string(), rejected_at: chrono::Utc::now().to_rfc3339() };
+    if let Ok(json) = serde_json::to_vec(&record) { let _ = tokio::fs::write(record_path(&qdir, &stored), json).await; }
+    tracing::warn!(bucket, file = %stored, reason, "upload quarantined");
+    true
+}
+
+fn quarantine_dir(state: &AppState) -> Option<std::path::PathBuf> {
+    state.quarantine_bucket.as_deref().and_then(|b| state.resolve_bucket_path(b))
+}
+
+fn not_configured() -> axum::response::Response {
+    (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"未配置隔离储存桶"}))).into_response()
+}
+
+fn describe(dir: &Path, name: &str) -> Option<serde_json::Value> {
+    let m = fs::metadata(dir.join(name)).ok().filter(|m| m.is_file())?;
+    let record = fs::read(record_path(dir, name)).ok().and_then(|b| serde_json::from_slice::<QuarantineRecord>(&b).ok());
+    Some(serde_json::json!({"name": name, "size": m.len(), "modified": format_time(m.modified().ok()), "record": record}))
+}
+
+pub async fn list_quarantine(State(state): State<AppState>) -> impl IntoResponse {
+    let Some(dir) = quarantine_dir(&state) else { return not_configured() };
+    let mut files = Vec::new();
+    if let Ok(iter) = fs::read_dir(&dir) {
+        for entry in iter.filter_map(Result::ok) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') { continue; }
+            if let Some(v) = describe(&dir, &name) { files.push(v); }
+        }
+    }
+    axum::Json(serde_json::json!({"bucket": state.quarantine_bucket, "files": files})).into_response()
+}
+
+pub async fn inspect_quarantine(State(state): State<AppState>, AxPath(filename): AxPath<String>) -> impl IntoResponse {
+    let Some(dir) = quarantine_dir(&state) else { return not_configured() };
+    if filename.starts_with('.') || filename.contains('/') || filename.contains('\\') { return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"文件名不合法"}))).into_response(); }
+    match describe(&dir, &filename) {
+        Some(v) => axum::Json(v).into_response(),
+        None => (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"文件不存在"}))).into_response(),
+    }
+}
+
+pub async fn purge_quarantine_file(State(state): State<AppState>, AxPath(filename): AxPath<String>) -> impl IntoResponse {
+    let Some(dir) = quarantine_dir(&state) else { return not_configured() };
+    if filename.starts_with('.') || filename.contains('/') || filename.contains('\\') { return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"文件名不合法"}))).into_response(); }
+    match fs::remove_file(dir.join(&filename)) {
+        Ok(_) => { let _ = fs::remove_file(record_path(&dir, &filename)); axum::Json(serde_json::json!({"success": true, "purged": [filename]})).into_response() }
+        Err(_) => (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"文件不存在"}))).into_response(),
+    }
+}
+
+pub async fn purge_quarantine(State(state): State<AppState>) -> impl IntoResponse {
+    let Some(dir) = quarantine_dir(&state) else { return not_configured() };
+    let mut purged = Vec::new();
+    if let Ok(iter) = fs::read_dir(&dir) {
+        for entry in iter.filter_map(Result::ok) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') || !entry.path().is_file() { continue; }
+            if fs::remove_file(entry.path()).is_ok() { let _ = fs::remove_file(record_path(&dir, &name)); purged.push(name); }
+        }
+    }
+    axum::Json(serde_json::json!({"success": true, "purged": purged})).into_response()
+}
@@ -0,0 +1,181 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use axum::{body::Body, extract::{ConnectInfo, State}, http::{HeaderValue, StatusCode, header}, response::IntoResponse};
+
+use crate::auth::{AuthedKey, authenticate};
+use crate::state::AppState;
+
+/// Classic token bucket: refills at `rate` tokens per second up to `capacity`.
+pub struct TokenBucket { tokens: f64, last: Instant, rate: f64, capacity: f64 }
+
+pub enum Decision {
+    Allowed { remaining: u64 },
+    Limited { retry_after_secs: u64 },
+}
+
+impl TokenBucket {
+    fn new(rate: f64, capacity: f64) -> Self { TokenBucket { tokens: capacity, last: Instant::now(), rate, capacity } }
+
+    fn take(&mut self) -> Decision {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.capacity);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Decision::Allowed { remaining: self.tokens.floor() as u64 }
+        } else {
+            Decision::Limited { retry_after_secs: ((1.0 - self.tokens) / self.rate).ceil().max(1.0) as u64 }
+        }
+    }
+
+    /// Untouched long enough to have refilled; dropping it changes nothing for its client.
+    fn is_idle(&self, now: Instant) -> bool {
+        now.duration_since(self.last).as_secs_f64() * self.rate + self.tokens >= self.capacity
+    }
+}
+
+/// Spends one token from `key`'s bucket, which holds up to `capacity` tokens.
+pub fn check(state: &AppState, key: &str, rate: f64, capacity: f64) -> Decision {
+    state.rate_limits.entry(key.to_string()).or_insert_with(|| TokenBucket::new(rate, capacity)).take()
+}
+
+const REMAINING: &str = "x-ratelimit-remaining";
+
+/// Throttles requests that don't carry a valid API key to `RATE_LIMIT_RPM` per peer IP (a
+/// minute's worth may burst). Runs before authentication, so floods with missing or wrong
+/// keys are cut off here; requests with a valid key are counted per key by `rate_limit`.
+pub async fn limit_clients(State(state): State<AppState>, req: axum::http::Request<Body>, next: axum::middleware::Next) -> axum::response::Response {
+    if state.rate_limit_rpm == 0 || matches!(authenticate(&state, req.headers()), Ok(Some(_))) { return next.run(req).await; }
+    let rpm = f64::from(state.rate_limit_rpm);
+    let remaining = match check(&state, &client_key(&req), rpm / 60.0, rpm) {
+        Decision::Allowed { remaining } => remaining,
+        Decision::Limited { retry_after_secs } => return too_many_requests(retry_after_secs),
+    };
+    let mut resp = next.run(req).await;
+    resp.headers_mut().insert(REMAINING, HeaderValue::from(remaining));
+    resp
+}
+
+/// Throttles each authenticated API key to `RATE_LIMIT_RPM`, counted under the key's hash
+/// so tenants behind one proxy don't share an allowance, and to the `rps` configured for
+/// it in `API_KEYS` (a second's worth may burst). Runs after `auth_middleware`.
+pub async fn rate_limit(State(state): State<AppState>, req: axum::http::Request<Body>, next: axum::middleware::Next) -> axum::response::Response {
+    let Some(AuthedKey { label, rps, key_hash }) = req.extensions().get::<AuthedKey>().cloned() else { return next.run(req).await };
+    let mut remaining: Option<u64> = None;
+    if state.rate_limit_rpm > 0 {
+        let rpm = f64::from(state.rate_limit_rpm);
+        match check(&state, &format!("rpm:key:{}", key_hash), rpm / 60.0, rpm) {
+            Decision::Allowed { remaining: left } => remaining = Some(left),
+            Decision::Limited { retry_after_secs } => return too_many_requests(retry_after_secs),
+        }
+    }
+    if let Some(rps) = rps {
+        match check(&state, &format!("key:{}", label), rps, rps.max(1.0)) {
+            Decision::Allowed { remaining: left } => remaining = Some(remaining.map_or(left, |r| r.min(left))),
+            Decision::Limited { retry_after_secs } => return too_many_requests(retry_after_secs),
+        }
+    }
+    let mut resp = next.run(req).await;
+    if let Some(remaining) = remaining { resp.headers_mut().insert(REMAINING, HeaderValue::from(remaining)); }
+    resp
+}
+
+/// `RATE_LIMIT_RPM` bucket for a request; the port is left out since every connection
+/// gets a new one.
+fn client_key(req: &axum::http::Request<Body>) -> String {
+    match req.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => format!("rpm:ip:{}", addr.ip()),
+        None => "rpm:unknown".to_string(),
+    }
+}
+
+fn too_many_requests(retry_after_secs: u64) -> axum::response::Response {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, retry_after_secs.to_string()), (header::HeaderName::from_static(REMAINING), "0".to_string())],
+        axum::Json(serde_json::json!({"error":"请求过于频繁，请稍后重试"})),
+    ).into_response()
+}
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Drops idle buckets so one-off clients don't accumulate.
+pub async fn prune_task(state: AppState) {
+    let mut ticker = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let now = Instant::now();
+        state.rate_limits.retain(|_, bucket| !bucket.is_idle(now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::body::Body;
+    use axum::extract::ConnectInfo;
+    use axum::http::{Request, StatusCode};
+
+    use crate::auth::ApiKey;
+    use crate::testing::{TempRoot, send, test_state};
+
+    fn from(ip: &str, key: Option<&str>) -> Request<Body> {
+        let mut req = Request::get("/api/buckets");
+        if let Some(key) = key { req = req.header("x-api-key", key); }
+        let mut req = req.body(Body::empty()).unwrap();
+        req.extensions_mut().insert(ConnectInfo(format!("{}:40000", ip).parse::<SocketAddr>().unwrap()));
+        req
+    }
+
+    #[tokio::test]
+    async fn client_is_limited_once_its_rpm_is_spent() {
+        let root = TempRoot::new();
+        let mut state = test_state(root.path());
+        state.rate_limit_rpm = 2;
+        assert_eq!(send(&state, from("10.0.0.1", None)).await.status, StatusCode::OK);
+        assert_eq!(send(&state, from("10.0.0.1", None)).await.status, StatusCode::OK);
+        let limited = send(&state, from("10.0.0.1", None)).await;
+        assert_eq!(limited.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(limited.header("retry-after"), Some("30"));
+        assert_eq!(send(&state, from("10.0.0.2", None)).await.status, StatusCode::OK, "other clients keep their own allowance");
+    }
+
+    #[tokio::test]
+    async fn bad_keys_are_limited_by_ip() {
+        let root = TempRoot::new();
+        let mut state = test_state(root.path());
+        state.rate_limit_rpm = 2;
+        state.api_key = Some("s3cret-key".to_string());
+        assert_eq!(send(&state, from("10.0.0.1", Some("wrong"))).await.status, StatusCode::FORBIDDEN);
+        assert_eq!(send(&state, from("10.0.0.1", Some("wrong"))).await.status, StatusCode::FORBIDDEN);
+        assert_eq!(send(&state, from("10.0.0.1", Some("wrong"))).await.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(send(&state, from("10.0.0.1", Some("s3cret-key"))).await.status, StatusCode::OK, "a valid key is counted on its own");
+    }
+
+    #[tokio::test]
+    async fn keys_behind_one_ip_have_their_own_rpm() {
+        let root = TempRoot::new();
+        let mut state = test_state(root.path());
+        state.rate_limit_rpm = 2;
+        state.api_keys = std::sync::Arc::new(vec![
+            ApiKey { label: "a".to_string(), key: "key-a".to_string(), rps: None },
+            ApiKey { label: "b".to_string(), key: "key-b".to_string(), rps: None },
+        ]);
+        for _ in 0..2 { assert_eq!(send(&state, from("10.0.0.1", Some("key-a"))).await.status, StatusCode::OK); }
+        assert_eq!(send(&state, from("10.0.0.1", Some("key-a"))).await.status, StatusCode::TOO_MANY_REQUESTS);
+        let other = send(&state, from("10.0.0.1", Some("key-b"))).await;
+        assert_eq!((other.status, other.header("x-ratelimit-remaining")), (StatusCode::OK, Some("1")));
+        assert_eq!(send(&state, from("10.0.0.2", Some("key-a"))).await.status, StatusCode::TOO_MANY_REQUESTS, "a key's allowance follows it across IPs");
+    }
+
+    #[tokio::test]
+    async fn each_key_has_its_own_rps() {
+        let root = TempRoot::new();
+        let mut state = test_state(root.path());
+        state.api_keys = std::sync::Arc::new(vec![ApiKey { label: "ci".to_string(), key: "ci-key".to_string(), rps: Some(1.0) }]);
+        let first = send(&state, from("10.0.0.1", Some("ci-key"))).await;
+        assert_eq!((first.status, first.header("x-ratelimit-remaining")), (StatusCode::OK, Some("0")));
+        assert_eq!(send(&state, from("10.0.0.2", Some("ci-key"))).await.status, StatusCode::TOO_MANY_REQUESTS);
+    }
+}
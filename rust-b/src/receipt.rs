@@ -0,0 +1,55 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::state::AppState;
+
+/// Proof that the server stored a file, signed with `SIGNING_SECRET`.
+#[derive(Serialize, Deserialize)]
+pub struct Receipt {
+    pub bucket: String,
+    /// Stored (on-disk) name, which identifies the exact version accepted.
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+    /// RFC 3339 time at which the upload was accepted.
+    pub timestamp: String,
+    /// Hex HMAC-SHA256 over the fields above; see `signing_input`.
+    pub signature: String,
+}
+
+/// Newline-separated fields in a fixed order, so no field can bleed into the next.
+fn signing_input(bucket: &str, name: &str, size: u64, sha256: &str, timestamp: &str) -> String {
+    format!("fileio-receipt-v1\n{}\n{}\n{}\n{}\n{}", bucket, name, size, sha256, timestamp)
+}
+
+pub(crate) fn mac(secret: &str, input: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(input.as_bytes());
+    mac
+}
+
+/// Signs a receipt for a stored file; `None` when no `SIGNING_SECRET` is configured.
+pub fn issue_receipt(state: &AppState, bucket: &str, name: &str, size: u64, sha256: &str) -> Option<Receipt> {
+    let secret = state.signing_secret.as_ref()?;
+    let timestamp = chrono::Utc::now().to_rfc3339();
+    let signature = hex::encode(mac(secret, &signing_input(bucket, name, size, sha256, &timestamp)).finalize().into_bytes());
+    Some(Receipt { bucket: bucket.to_string(), name: name.to_string(), size, sha256: sha256.to_string(), timestamp, signature })
+}
+
+fn verify(secret: &str, r: &Receipt) -> bool {
+    let Ok(signature) = hex::decode(&r.signature) else { return false };
+    // `verify_slice` compares in constant time.
+    mac(secret, &signing_input(&r.bucket, &r.name, r.size, &r.sha256, &r.timestamp)).verify_slice(&signature).is_ok()
+}
+
+/// Checks a receipt previously returned by `upload_file`.
+pub async fn verify_receipt(State(state): State<AppState>, axum::Json(receipt): axum::Json<Receipt>) -> impl IntoResponse {
+    let Some(secret) = &state.signing_secret else { return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"未配置签名密钥"}))).into_response() };
+    if verify(secret, &receipt) {
+        axum::Json(serde_json::json!({"valid": true, "bucket": receipt.bucket, "name": receipt.name, "size": receipt.size, "sha256": receipt.sha256, "timestamp": receipt.timestamp})).into_response()
+    } else {
+        (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"valid": false, "error":"回执签名无效"}))).into_response()
+    }
+}
@@ -1,36 +1,122 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use redis::AsyncCommands;
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use tokio::sync::OnceCell;
+
+/// A single shared Redis connection that reconnects on its own after a drop.
+/// It is opened on first use, so startup neither waits on nor fails because of Redis.
+/// Clones share the connection.
+#[derive(Clone)]
+pub struct RedisPool {
+    client: redis::Client,
+    conn: Arc<OnceCell<ConnectionManager>>,
+}
+
+impl RedisPool {
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        Ok(RedisPool { client: redis::Client::open(url)?, conn: Arc::new(OnceCell::new()) })
+    }
+
+    /// Whether the shared connection has been established yet.
+    pub fn is_initialized(&self) -> bool {
+        self.conn.initialized()
+    }
+
+    async fn conn(&self) -> anyhow::Result<ConnectionManager> {
+        // No backoff and short timeouts keep requests from stalling on an unreachable Redis;
+        // the next caller simply tries again.
+        let config = ConnectionManagerConfig::new()
+            .set_number_of_retries(0)
+            .set_connection_timeout(Duration::from_secs(2))
+            .set_response_timeout(Duration::from_secs(2));
+        let conn = self.conn.get_or_try_init(|| ConnectionManager::new_with_config(self.client.clone(), config)).await?;
+        Ok(conn.clone())
+    }
+}
 
-pub async fn set_key(url: &str, key: &str, value: &str) -> anyhow::Result<()> {
-    let client = redis::Client::open(url)?;
-    let mut conn = client.get_async_connection().await?;
+pub async fn set_key(pool: &RedisPool, key: &str, value: &str) -> anyhow::Result<()> {
+    let mut conn = pool.conn().await?;
     conn.set::<_, _, ()>(key, value).await?;
     Ok(())
 }
 
-pub async fn get_key(url: &str, key: &str) -> anyhow::Result<Option<String>> {
-    let client = redis::Client::open(url)?;
-    let mut conn = client.get_async_connection().await?;
+pub async fn get_key(pool: &RedisPool, key: &str) -> anyhow::Result<Option<String>> {
+    let mut conn = pool.conn().await?;
     let res: Option<String> = conn.get(key).await?;
     Ok(res)
 }
 
-pub async fn del_key(url: &str, key: &str) -> anyhow::Result<()> {
-    let client = redis::Client::open(url)?;
-    let mut conn = client.get_async_connection().await?;
+pub async fn del_key(pool: &RedisPool, key: &str) -> anyhow::Result<()> {
+    let mut conn = pool.conn().await?;
     let _: () = conn.del(key).await?;
     Ok(())
 }
 
-pub async fn register_node(url: &str, node_json: &str) -> anyhow::Result<()> {
-    let client = redis::Client::open(url)?;
-    let mut conn = client.get_async_connection().await?;
-    let _: () = conn.sadd("nodes", node_json).await?;
+/// `SET key value EX ttl`.
+pub async fn set_ex(pool: &RedisPool, key: &str, value: &str, ttl_secs: u64) -> anyhow::Result<()> {
+    let mut conn = pool.conn().await?;
+    let _: () = conn.set_ex(key, value, ttl_secs).await?;
     Ok(())
 }
 
-pub async fn list_nodes(url: &str) -> anyhow::Result<Vec<String>> {
-    let client = redis::Client::open(url)?;
-    let mut conn = client.get_async_connection().await?;
-    let members: Vec<String> = conn.smembers("nodes").await?;
-    Ok(members)
-}
\ No newline at end of file
+/// All keys matching `pattern`, walked with `SCAN` so a large keyspace never blocks the server.
+pub async fn scan_keys(pool: &RedisPool, pattern: &str) -> anyhow::Result<Vec<String>> {
+    let mut conn = pool.conn().await?;
+    let mut keys: Vec<String> = Vec::new();
+    let mut cursor: u64 = 0;
+    loop {
+        let (next, batch): (u64, Vec<String>) = redis::cmd("SCAN").arg(cursor).arg("MATCH").arg(pattern).arg("COUNT").arg(100).query_async(&mut conn).await?;
+        keys.extend(batch);
+        if next == 0 { break; }
+        cursor = next;
+    }
+    keys.sort();
+    keys.dedup();
+    Ok(keys)
+}
+
+pub async fn exists(pool: &RedisPool, key: &str) -> anyhow::Result<bool> {
+    let mut conn = pool.conn().await?;
+    Ok(conn.exists(key).await?)
+}
+
+/// Values of `keys`, skipping any that no longer exist.
+pub async fn mget(pool: &RedisPool, keys: &[String]) -> anyhow::Result<Vec<String>> {
+    if keys.is_empty() { return Ok(Vec::new()); }
+    let mut conn = pool.conn().await?;
+    let values: Vec<Option<String>> = redis::cmd("MGET").arg(keys).query_async(&mut conn).await?;
+    Ok(values.into_iter().flatten().collect())
+}
+
+pub async fn hset(pool: &RedisPool, key: &str, field: &str, value: &str) -> anyhow::Result<()> {
+    let mut conn = pool.conn().await?;
+    let _: () = conn.hset(key, field, value).await?;
+    Ok(())
+}
+
+pub async fn hget(pool: &RedisPool, key: &str, field: &str) -> anyhow::Result<Option<String>> {
+    let mut conn = pool.conn().await?;
+    let res: Option<String> = conn.hget(key, field).await?;
+    Ok(res)
+}
+
+pub async fn hdel(pool: &RedisPool, key: &str, field: &str) -> anyhow::Result<()> {
+    let mut conn = pool.conn().await?;
+    let _: () = conn.hdel(key, field).await?;
+    Ok(())
+}
+
+pub async fn ping(pool: &RedisPool) -> anyhow::Result<bool> {
+    let mut conn = pool.conn().await?;
+    let res: String = redis::cmd("PING").query_async(&mut conn).await?;
+    Ok(res.to_uppercase() == "PONG")
+}
+
+/// `SET key value NX EX ttl`; `true` when the key was created.
+pub async fn set_nx_ex(pool: &RedisPool, key: &str, value: &str, ttl_secs: u64) -> anyhow::Result<bool> {
+    let mut conn = pool.conn().await?;
+    let res: Option<String> = redis::cmd("SET").arg(key).arg(value).arg("NX").arg("EX").arg(ttl_secs).query_async(&mut conn).await?;
+    Ok(res.is_some())
+}
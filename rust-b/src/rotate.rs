@@ -0,0 +1,72 @@
+use std::fs;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use axum::{extract::{Path as AxPath, State}, http::StatusCode, response::IntoResponse};
+
+use crate::cache::with_cache_control;
+use crate::handlers::Quota;
+use crate::state::AppState;
+use crate::meta::original_key_of;
+use crate::util::{format_time, join_key, split_key};
+
+/// Rotated segments of `stored` in `dir` as `(index, name)`, oldest first.
+pub fn segments_of(dir: &Path, stored: &str) -> Vec<(u32, String)> {
+    let Ok(iter) = fs::read_dir(dir) else { return Vec::new() };
+    let prefix = format!("{}.", stored);
+    let mut segments: Vec<(u32, String)> = iter
+        .filter_map(Result::ok)
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .filter_map(|name| {
+            let index = name.strip_prefix(&prefix)?.strip_suffix(".gz")?.parse().ok()?;
+            Some((index, name))
+        })
+        .collect();
+    segments.sort();
+    segments
+}
+
+/// Gzips `path` to `<name>.N.gz` and truncates it once it has reached `LOG_ROTATE_BYTES`.
+/// Must be called with the file's append lock held so no append lands mid-rotation.
+/// Returns the new segment's file name, which sits next to `path`, or a `QuotaExceeded`
+/// error when the bucket has no room for the segment.
+pub async fn rotate_if_needed(state: &AppState, path: &Path, quota: Option<&Quota>) -> std::io::Result<Option<String>> {
+    let Some(limit) = state.log_rotate_bytes else { return Ok(None) };
+    let len = match tokio::fs::metadata(path).await {
+        Ok(m) if m.len() >= limit => m.len(),
+        _ => return Ok(None),
+    };
+    // The segment sits beside the file until it is truncated, and may be nearly as large.
+    if quota.is_some_and(|q| len > q.left) { return Err(std::io::Error::new(std::io::ErrorKind::QuotaExceeded, "no room in the bucket for the rotated segment")); }
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let (Some(dir), Some(stored)) = (path.parent(), path.file_name().map(|n| n.to_string_lossy().to_string())) else { return Ok(None) };
+        let index = segments_of(dir, &stored).last().map_or(1, |(i, _)| i + 1);
+        let segment = format!("{}.{}.gz", stored, index);
+        // Written under a hidden name first so a half-written segment is never listed or served.
+        let tmp = dir.join(format!(".{}.tmp", segment));
+        let result = (|| {
+            let mut encoder = flate2::write::GzEncoder::new(BufWriter::new(fs::File::create(&tmp)?), flate2::Compression::default());
+            std::io::copy(&mut BufReader::new(fs::File::open(&path)?), &mut encoder)?;
+            encoder.finish()?.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            fs::rename(&tmp, dir.join(&segment))?;
+            fs::OpenOptions::new().write(true).open(&path)?.set_len(0)
+        })();
+        if result.is_err() { let _ = fs::remove_file(&tmp); }
+        result.map(|_| Some(segment))
+    }).await.map_err(std::io::Error::other)?
+}
+
+/// Lists the gzipped segments rotated out of an append-only file.
+pub async fn list_segments(State(state): State<AppState>, AxPath((bucket, filename)): AxPath<(String, String)>) -> impl IntoResponse {
+    if state.resolve_file_path(&bucket, &filename).is_none() { return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"文件名不合法"}))).into_response(); }
+    let Some(dir) = state.resolve_bucket_path(&bucket) else { return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"储存桶名称不合法"}))).into_response() };
+    let Some(stored) = crate::handlers::resolve_stored_name(&state, &bucket, &filename).await else { return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"文件不存在"}))).into_response() };
+    let (folder, leaf) = split_key(&stored);
+    let segments: Vec<serde_json::Value> = segments_of(&dir.join(folder), leaf).into_iter().filter_map(|(index, name)| {
+        let key = join_key(folder, &name);
+        let m = fs::metadata(dir.join(&key)).ok()?;
+        let shown = if state.clean_names { original_key_of(&dir, &key) } else { key };
+        Some(serde_json::json!({"index": index, "name": shown, "size": m.len(), "modified": format_time(m.modified().ok())}))
+    }).collect();
+    with_cache_control(&state, axum::Json(serde_json::json!({"file": filename, "bucket": bucket, "segments": segments})).into_response())
+}
@@ -1,26 +1,272 @@
-use axum::{routing::{get, post, delete}, Router};
-use tower_http::cors::{Any, CorsLayer};
+use axum::{body::Body, extract::{DefaultBodyLimit, FromRequest, Path as AxPath, Query, State}, http::{HeaderMap, Request, StatusCode, header}, response::{IntoResponse, Response}, routing::{get, post, delete}, Router};
+use futures_util::StreamExt;
+use tower::Layer;
+use tower_http::compression::{CompressionLayer, DefaultPredicate, Predicate};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, Any, CorsLayer};
+use tower_http::normalize_path::{NormalizePath, NormalizePathLayer};
+use tower_http::trace::TraceLayer;
 
 use crate::state::AppState;
+use crate::amplify::CompressionBudget;
 use crate::auth::auth_middleware;
-use crate::handlers::{list_buckets, create_bucket, delete_bucket, list_files, upload_file, download_file, delete_file, file_info, health, register_node_endpoint, list_nodes_endpoint};
+use crate::ratelimit::{limit_clients, rate_limit};
+use crate::receipt::verify_receipt;
+use crate::presign::{PresignReq, presign_url, public_download};
+use crate::checksum::verify_file;
+use crate::rotate::list_segments;
+use crate::trace::{TraceContext, propagate_trace};
+use crate::telemetry::{metrics_endpoint, track_requests};
+use crate::archive::{export_bucket, import_bucket};
+use crate::prune::prune_empty;
+use crate::quarantine::{list_quarantine, inspect_quarantine, purge_quarantine, purge_quarantine_file};
+use crate::handlers::{DownloadQuery, MoveReq, file_exists, list_buckets, create_bucket, delete_bucket, list_files, directory_index, resolve_name, upload_file, upload_progress, concat_files, download_file, append_file, delete_file, move_file, file_info, landing, health, health_status, structure, register_node_endpoint, list_nodes_endpoint};
 
 pub fn build_router(state: AppState) -> Router {
-    let cors = CorsLayer::new().allow_origin(Any).allow_methods(Any).allow_headers(Any);
+    let cors = cors_layer(&state);
     let authed = Router::new()
         .route("/api/buckets", get(list_buckets).post(create_bucket))
         .route("/api/buckets/:bucket", delete(delete_bucket))
         .route("/api/buckets/:bucket/files", get(list_files))
-        .route("/api/buckets/:bucket/upload", post(upload_file))
-        .route("/api/buckets/:bucket/files/:filename", get(download_file).delete(delete_file))
-        .route("/api/buckets/:bucket/files/:filename/info", get(file_info))
+        .route("/api/buckets/:bucket/index.html", get(directory_index))
+        .route("/api/buckets/:bucket/resolve", get(resolve_name))
+        .route("/api/buckets/:bucket/upload", post(upload_file).layer(DefaultBodyLimit::max(usize::try_from(state.max_upload_bytes).unwrap_or(usize::MAX))))
+        .route("/api/buckets/:bucket/concat", post(concat_files))
+        .route("/api/buckets/:bucket/export", get(export_bucket).layer(CompressionLayer::new().gzip(true).zstd(true).compress_when(DefaultPredicate::new().and(CompressionBudget { max_bytes: state.compression_max_bytes, counters: state.amplification.clone() }))))
+        .route("/api/buckets/:bucket/import", post(import_bucket))
+        .route("/api/uploads/:id/progress", get(upload_progress))
+        .route("/api/buckets/:bucket/files/*path", get(file_get).post(file_post).delete(delete_file))
+        // The same actions with the action first, for keys whose last segment is an action name.
+        .route("/api/buckets/:bucket/info/*path", get(file_info))
+        .route("/api/buckets/:bucket/append/*path", post(append_file))
+        .route("/api/buckets/:bucket/segments/*path", get(list_segments))
+        .route("/api/buckets/:bucket/verify/*path", get(verify_file))
+        .route("/api/buckets/:bucket/move/*path", post(move_file))
+        .route("/api/buckets/:bucket/presign/*path", post(presign_url))
         .route("/api/nodes/register", post(register_node_endpoint))
         .route("/api/nodes", get(list_nodes_endpoint))
+        .route("/api/admin/quarantine", get(list_quarantine).delete(purge_quarantine))
+        .route("/api/admin/prune-empty", post(prune_empty))
+        .route("/api/admin/quarantine/:filename", get(inspect_quarantine).delete(purge_quarantine_file))
+        .route_layer(axum::middleware::from_fn_with_state(state.clone(), rate_limit))
         .route_layer(axum::middleware::from_fn_with_state(state.clone(), auth_middleware))
+        // Ahead of authentication, so floods with bad keys are throttled as well.
+        .route_layer(axum::middleware::from_fn_with_state(state.clone(), limit_clients))
+        .route_layer(axum::middleware::from_fn_with_state(state.clone(), limit_connections))
         .with_state(state.clone());
+    // Unauthenticated downloads are only safe when every URL must carry a valid signature.
+    let public = if state.signing_secret.is_some() {
+        tracing::info!("SIGNING_SECRET 已配置，公共下载路由已启用");
+        public_routes()
+    } else {
+        tracing::info!("未配置 SIGNING_SECRET，公共下载路由未挂载");
+        Router::new()
+    };
     Router::new()
+        .route("/", get(landing))
         .route("/health", get(health))
+        .route("/health/status", get(health_status))
+        .route("/metrics", get(metrics_endpoint))
+        .route("/structure", get(structure))
         .merge(authed)
+        .merge(public)
+        .layer(axum::middleware::from_fn(track_requests))
+        .layer(axum::middleware::from_fn(reject_empty_segments))
         .layer(cors)
+        .layer(TraceLayer::new_for_http().make_span_with(|req: &Request<Body>| {
+            let ctx = req.extensions().get::<TraceContext>();
+            let request_id = ctx.map(|c| c.request_id.as_str()).unwrap_or_default();
+            let trace_id = ctx.map(|c| c.trace_id.as_str()).unwrap_or_default();
+            let parent_id = ctx.and_then(|c| c.parent_id.as_deref()).unwrap_or_default();
+            tracing::info_span!("request", method = %req.method(), uri = %req.uri(), request_id = %request_id, trace_id = %trace_id, parent_id = %parent_id)
+        }))
+        .layer(axum::middleware::from_fn(propagate_trace))
         .with_state(state)
-}
\ No newline at end of file
+}
+
+/// Any origin unless `ALLOWED_ORIGINS` is set. Listed origins may send credentials, so
+/// methods and headers are echoed back rather than wildcarded (browsers reject `*` there).
+/// The layer adds `Vary: Origin` itself.
+fn cors_layer(state: &AppState) -> CorsLayer {
+    if state.allowed_origins.is_empty() { return CorsLayer::new().allow_origin(Any).allow_methods(Any).allow_headers(Any); }
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::list(state.allowed_origins.iter().cloned()))
+        .allow_methods(AllowMethods::mirror_request())
+        .allow_headers(AllowHeaders::mirror_request())
+        .allow_credentials(true)
+}
+
+/// Routes reachable without an API key; mounted only when `SIGNING_SECRET` is set.
+fn public_routes() -> Router<AppState> {
+    Router::new()
+        .route("/api/verify-receipt", post(verify_receipt))
+        .route("/api/public/:bucket/*path", get(public_download))
+}
+
+/// Last segment of `/files/*path` when it names an action on the key before it, as in
+/// `images/logo.png/info`. A one-segment path is always a file.
+fn split_action<'a>(path: &'a str, actions: &[&str]) -> (&'a str, Option<&'a str>) {
+    match path.rsplit_once('/') {
+        Some((key, action)) if actions.contains(&action) => (key, Some(action)),
+        _ => (path, None),
+    }
+}
+
+/// `GET /files/*path`: a download, or `info`, `segments` or `verify` of the key. A stored
+/// file whose name ends in an action word is still downloaded: a key can't be both a file
+/// and the folder an action would be applied to.
+async fn file_get(State(state): State<AppState>, AxPath((bucket, path)): AxPath<(String, String)>, Query(q): Query<DownloadQuery>, trace: Option<axum::Extension<TraceContext>>, headers: HeaderMap) -> Response {
+    let (key, action) = match split_action(&path, &["info", "segments", "verify"]) {
+        (_, Some(_)) if file_exists(&state, &bucket, &path).await => (path.clone(), None),
+        (key, action) => (key.to_string(), action.map(str::to_string)),
+    };
+    match action.as_deref() {
+        Some("info") => file_info(State(state), AxPath((bucket, key)), headers).await.into_response(),
+        Some("segments") => list_segments(State(state), AxPath((bucket, key))).await.into_response(),
+        Some("verify") => verify_file(State(state), AxPath((bucket, key))).await.into_response(),
+        _ => download_file(State(state), AxPath((bucket, key)), Query(q), trace, headers).await.into_response(),
+    }
+}
+
+/// `POST /files/*path`: `append`, `move` or `presign` of the key; a bare key has no `POST`.
+async fn file_post(State(state): State<AppState>, AxPath((bucket, path)): AxPath<(String, String)>, req: Request<Body>) -> Response {
+    let (key, action) = split_action(&path, &["append", "move", "presign"]);
+    let (key, action) = (key.to_string(), action.map(str::to_string));
+    match action.as_deref() {
+        Some("append") => append_file(State(state), AxPath((bucket, key)), req.into_body()).await.into_response(),
+        Some("move") => match axum::Json::<MoveReq>::from_request(req, &state).await {
+            Ok(payload) => move_file(State(state), AxPath((bucket, key)), payload).await.into_response(),
+            Err(rejection) => rejection.into_response(),
+        },
+        Some("presign") => {
+            let payload = Option::<axum::Json<PresignReq>>::from_request(req, &state).await.unwrap_or(None);
+            presign_url(State(state), AxPath((bucket, key)), payload).await.into_response()
+        }
+        _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+    }
+}
+
+/// The router wrapped so trailing slashes are trimmed before routing.
+pub fn build_app(state: AppState) -> NormalizePath<Router> {
+    NormalizePathLayer::trim_trailing_slash().layer(build_router(state))
+}
+
+/// `/api/buckets//files` would otherwise fall through to an unrelated 404/405.
+async fn reject_empty_segments(req: Request<Body>, next: axum::middleware::Next) -> Response {
+    if req.uri().path().contains("//") {
+        return (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({"error":"路径中包含空的储存桶或文件名"}))).into_response();
+    }
+    next.run(req).await
+}
+
+/// Caps in-flight API requests at `MAX_CONNECTIONS`, answering `503` instead of queueing.
+/// Health probes are routed outside this layer so they keep answering under load.
+async fn limit_connections(State(state): State<AppState>, req: Request<Body>, next: axum::middleware::Next) -> Response {
+    let Ok(permit) = state.connections.clone().try_acquire_owned() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, [(header::RETRY_AFTER, "1")], axum::Json(serde_json::json!({"error":"服务器繁忙，请稍后重试"}))).into_response();
+    };
+    // Downloads keep streaming after the handler returns; release the slot only once the body is done.
+    next.run(req).await.map(|body| Body::from_stream(body.into_data_stream().map(move |chunk| { let _held = &permit; chunk })))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::body::Body;
+    use axum::http::{HeaderValue, Request, StatusCode};
+
+    use crate::testing::{TempRoot, create_bucket, file_part, get, send, test_state, upload};
+
+    fn preflight(origin: &str) -> Request<Body> {
+        Request::options("/api/buckets/docs/files/a.txt")
+            .header("origin", origin)
+            .header("access-control-request-method", "DELETE")
+            .header("access-control-request-headers", "x-api-key")
+            .body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn trailing_slash_reaches_the_same_route() {
+        let root = TempRoot::new();
+        let state = test_state(root.path());
+        create_bucket(&state, "docs").await;
+        upload(&state, "/api/buckets/docs/upload", &[file_part("a.txt", b"a")]).await;
+        let plain = get(&state, "/api/buckets/docs/files").await;
+        let slashed = get(&state, "/api/buckets/docs/files/").await;
+        assert_eq!(slashed.status, StatusCode::OK);
+        assert_eq!(plain.json()["files"].as_array().map(Vec::len), Some(1));
+        assert_eq!(slashed.json()["files"], plain.json()["files"]);
+        assert_eq!(get(&state, "/api/buckets/").await.status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn empty_segments_are_rejected() {
+        let root = TempRoot::new();
+        let state = test_state(root.path());
+        create_bucket(&state, "docs").await;
+        assert_eq!(get(&state, "/api/buckets//files").await.status, StatusCode::BAD_REQUEST);
+        assert_eq!(get(&state, "/api/buckets/docs/files//a.txt").await.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn public_route_is_only_mounted_with_a_signing_secret() {
+        let root = TempRoot::new();
+        let mut state = test_state(root.path());
+        let absent = get(&state, "/api/public/docs/a.txt").await;
+        assert_eq!(absent.status, StatusCode::NOT_FOUND);
+        assert!(absent.body.is_empty(), "the router's own 404, not the handler's");
+        state.signing_secret = Some("secret".to_string());
+        assert_eq!(get(&state, "/api/public/docs/a.txt").await.status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn preflight_answers_only_listed_origins() {
+        let root = TempRoot::new();
+        let mut state = test_state(root.path());
+        state.api_key = Some("s3cret-key".to_string());
+        state.allowed_origins = Arc::new(vec![HeaderValue::from_static("https://app.example.com")]);
+        let allowed = send(&state, preflight("https://app.example.com")).await;
+        assert_eq!(allowed.status, StatusCode::OK, "preflights carry no key and must not hit auth");
+        assert_eq!(allowed.header("access-control-allow-origin"), Some("https://app.example.com"));
+        assert_eq!(allowed.header("access-control-allow-credentials"), Some("true"));
+        assert_eq!(allowed.header("access-control-allow-methods"), Some("DELETE"));
+        assert_eq!(allowed.header("access-control-allow-headers"), Some("x-api-key"));
+        let refused = send(&state, preflight("https://evil.example.com")).await;
+        assert_eq!(refused.header("access-control-allow-origin"), None);
+    }
+
+    #[tokio::test]
+    async fn any_origin_without_a_list() {
+        let root = TempRoot::new();
+        let state = test_state(root.path());
+        let resp = send(&state, preflight("https://anywhere.example.com")).await;
+        assert_eq!(resp.header("access-control-allow-origin"), Some("*"));
+        assert_eq!(resp.header("access-control-allow-credentials"), None);
+    }
+
+    #[tokio::test]
+    async fn actions_dispatch_from_the_files_route() {
+        let root = TempRoot::new();
+        let mut state = test_state(root.path());
+        state.clean_names = true;
+        create_bucket(&state, "docs").await;
+        upload(&state, "/api/buckets/docs/upload", &[file_part("a.txt", b"abc")]).await;
+        upload(&state, "/api/buckets/docs/upload?path=notes", &[file_part("info", b"a file called info")]).await;
+
+        let info = get(&state, "/api/buckets/docs/files/a.txt/info").await;
+        assert_eq!(info.status, StatusCode::OK);
+        assert_eq!(info.json()["size"].as_u64(), Some(3));
+        assert_eq!(get(&state, "/api/buckets/docs/info/a.txt").await.json()["size"].as_u64(), Some(3), "the prefixed form is an alias");
+        assert_eq!(get(&state, "/api/buckets/docs/files/notes/info").await.body, b"a file called info");
+        assert_eq!(get(&state, "/api/buckets/docs/files/a.txt/verify").await.json()["valid"], true);
+
+        let appended = send(&state, Request::post("/api/buckets/docs/files/a.txt/append").body(Body::from("def")).unwrap()).await;
+        assert_eq!(appended.status, StatusCode::OK);
+        assert_eq!(get(&state, "/api/buckets/docs/files/a.txt").await.body, b"abcdef");
+        let bare = send(&state, Request::post("/api/buckets/docs/files/a.txt").body(Body::empty()).unwrap()).await;
+        assert_eq!(bare.status, StatusCode::METHOD_NOT_ALLOWED);
+        let moved = send(&state, Request::post("/api/buckets/docs/files/a.txt/move").header("content-type", "application/json").body(Body::from(r#"{"targetBucket":"archive"}"#)).unwrap()).await;
+        assert_eq!(moved.status, StatusCode::OK);
+        assert_eq!(get(&state, "/api/buckets/archive/files/a.txt").await.body, b"abcdef");
+    }
+}
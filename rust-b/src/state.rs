@@ -1,24 +1,153 @@
-use std::{env, path::PathBuf};
+use std::{collections::{HashMap, HashSet}, env, path::PathBuf, sync::Arc, time::Duration};
+
+use dashmap::DashMap;
+use tokio::sync::Semaphore;
+
+use crate::amplify::AmplificationCounters;
+use crate::auth::{ApiKey, parse_api_keys};
+use crate::cas::{blob_path, is_content_hash};
+use crate::cold::ColdTier;
+use crate::ratelimit::TokenBucket;
+use crate::index::{IndexStore, build_index};
+use crate::redis::RedisPool;
+use crate::util::{is_plain_name, is_safe_key, safe_bucket_dir, safe_join};
 
 #[derive(Clone)]
 pub struct AppState {
     pub root_dir: PathBuf,
+    /// Buckets from `BUCKET_PATHS` that live outside `root_dir`.
+    pub bucket_paths: Arc<HashMap<String, PathBuf>>,
     pub api_key: Option<String>,
-    pub redis_url: Option<String>,
+    pub api_keys: Arc<Vec<ApiKey>>,
+    pub rate_limits: Arc<DashMap<String, TokenBucket>>,
+    /// Requests per minute allowed per client; `0` means unlimited (`RATE_LIMIT_RPM`).
+    pub rate_limit_rpm: u32,
+    pub signing_secret: Option<String>,
+    pub redis: Option<RedisPool>,
+    /// File locations and live nodes (`INDEX_BACKEND`).
+    pub index: Option<Arc<dyn IndexStore>>,
     pub public_host: String,
+    /// This node's id in the index (`NODE_ID`, else `PUBLIC_HOST:PORT`); stable across restarts.
+    pub node_id: String,
+    /// Browser origins allowed by CORS (`ALLOWED_ORIGINS`); empty allows any origin.
+    pub allowed_origins: Arc<Vec<axum::http::HeaderValue>>,
+    pub proxy_mode: bool,
+    pub http: reqwest::Client,
+    pub clean_names: bool,
+    /// Store uploads under their SHA-256 and map names onto hashes (`CONTENT_ADDRESSED`).
+    pub content_addressed: bool,
+    /// Also enforce S3 bucket-naming rules on new buckets (`S3_COMPAT_NAMES`).
+    pub s3_compat_names: bool,
+    pub name_reservation: bool,
+    pub quarantine_bucket: Option<String>,
+    pub protected_buckets: Arc<HashSet<String>>,
+    pub prune_min_age: Duration,
+    pub bucket_writers: Arc<DashMap<String, usize>>,
+    pub uploads: Arc<DashMap<String, UploadProgress>>,
+    pub cache_control: String,
+    pub directory_index: bool,
+    pub landing_page: bool,
+    /// Per-bucket time budget for the size/count walk in `list_buckets`; `None` means unbounded.
+    pub listing_walk_timeout: Option<Duration>,
+    pub generations: Arc<DashMap<String, u64>>,
+    pub boot_id: String,
+    pub node_list_max: usize,
+    pub max_connections: usize,
+    pub connections: Arc<Semaphore>,
+    pub max_upload_bytes: u64,
+    /// `/health/status` reports not ready once `ROOT_DIR` has less free space than this
+    /// (`MIN_FREE_BYTES`); `0` disables the check.
+    pub min_free_bytes: u64,
+    /// Appended files are gzipped and restarted once they reach this size (`LOG_ROTATE_BYTES`).
+    pub log_rotate_bytes: Option<u64>,
+    pub max_filename_bytes: usize,
+    pub max_content_type_bytes: usize,
+    pub require_content_type: bool,
+    pub file_locks: Arc<DashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>,
+    pub cold: Option<ColdTier>,
+    /// Responses larger than this are sent uncompressed (`COMPRESSION_MAX_BYTES`).
+    pub compression_max_bytes: u64,
+    pub amplification: Arc<AmplificationCounters>,
+    /// Renders `/metrics`; set in `main` once the recorder is installed.
+    pub metrics: Option<metrics_exporter_prometheus::PrometheusHandle>,
+    /// Paths with a background rehydration in flight.
+    pub rehydrating: Arc<DashMap<PathBuf, ()>>,
 }
 
-pub fn build_state() -> AppState {
+/// Bytes received so far for an upload that carries an `Upload-Id` header.
+#[derive(Clone, Copy, Default)]
+pub struct UploadProgress {
+    pub received: u64,
+    pub total: Option<u64>,
+}
+
+pub fn build_state() -> anyhow::Result<AppState> {
     let root_dir = env::var("ROOT_DIR").unwrap_or_else(|_| "./storage".to_string());
     let api_key = env::var("API_KEY").ok().filter(|v| !v.is_empty());
-    let redis_url = build_redis_url();
+    let api_keys = parse_api_keys(&env::var("API_KEYS").unwrap_or_default())?;
+    let signing_secret = env::var("SIGNING_SECRET").ok().filter(|v| !v.is_empty());
+    let redis = build_redis_url().map(|url| RedisPool::new(&url)).transpose()?;
     let public_host = env::var("PUBLIC_HOST").unwrap_or_else(|_| "localhost".to_string());
-    AppState {
+    let clean_names = env_flag("CLEAN_NAMES");
+    let name_reservation = clean_names && env_flag("NAME_RESERVATION");
+    let cache_control = env::var("LISTING_CACHE_CONTROL").ok().filter(|v| !v.is_empty()).unwrap_or_else(|| "no-store".to_string());
+    let max_connections: usize = env::var("MAX_CONNECTIONS").ok().and_then(|s| s.parse().ok()).unwrap_or(0);
+    let max_upload_bytes: u64 = env::var("MAX_UPLOAD_BYTES").ok().and_then(|s| s.parse().ok()).unwrap_or(100 * 1024 * 1024);
+    let max_filename_bytes: usize = env::var("MAX_FILENAME_BYTES").ok().and_then(|s| s.parse().ok()).unwrap_or(255);
+    let max_content_type_bytes: usize = env::var("MAX_CONTENT_TYPE_BYTES").ok().and_then(|s| s.parse().ok()).unwrap_or(255);
+    let bucket_paths = parse_bucket_paths(&env::var("BUCKET_PATHS").unwrap_or_default())?;
+    let cold = env::var("COLD_DIR").ok().filter(|v| !v.trim().is_empty()).map(|dir| ColdTier {
+        dir: PathBuf::from(dir.trim()),
+        after: Duration::from_secs(86400 * env::var("COLD_AFTER_DAYS").ok().and_then(|s| s.parse::<u64>().ok()).unwrap_or(30)),
+        inline_bytes: env::var("COLD_INLINE_BYTES").ok().and_then(|s| s.parse().ok()).unwrap_or(8 * 1024 * 1024),
+    });
+    let quarantine_bucket = env::var("QUARANTINE_BUCKET").ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+    Ok(AppState {
         root_dir: PathBuf::from(root_dir),
+        bucket_paths: Arc::new(bucket_paths),
         api_key,
-        redis_url,
+        api_keys: Arc::new(api_keys),
+        rate_limits: Arc::new(DashMap::new()),
+        rate_limit_rpm: env::var("RATE_LIMIT_RPM").ok().and_then(|s| s.trim().parse().ok()).unwrap_or(0),
+        signing_secret,
+        index: build_index(&env::var("INDEX_BACKEND").unwrap_or_default().trim().to_ascii_lowercase(), redis.as_ref())?,
+        redis,
+        node_id: env::var("NODE_ID").ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty()).unwrap_or_else(|| format!("{}:{}", public_host, port_from_env())),
         public_host,
-    }
+        allowed_origins: Arc::new(parse_allowed_origins(&env::var("ALLOWED_ORIGINS").unwrap_or_default())?),
+        proxy_mode: env_flag("PROXY_MODE"),
+        http: reqwest::Client::builder().connect_timeout(Duration::from_secs(5)).build()?,
+        clean_names,
+        content_addressed: env_flag("CONTENT_ADDRESSED"),
+        s3_compat_names: env_flag("S3_COMPAT_NAMES"),
+        name_reservation,
+        quarantine_bucket,
+        protected_buckets: Arc::new(env::var("PROTECTED_BUCKETS").unwrap_or_default().split(',').map(str::trim).filter(|b| !b.is_empty()).map(str::to_string).collect()),
+        prune_min_age: Duration::from_secs(env::var("PRUNE_MIN_AGE_SECS").ok().and_then(|s| s.parse().ok()).unwrap_or(3600)),
+        bucket_writers: Arc::new(DashMap::new()),
+        uploads: Arc::new(DashMap::new()),
+        cache_control,
+        directory_index: env_flag("DIRECTORY_INDEX_ENABLED"),
+        landing_page: env::var("LANDING_PAGE").map(|_| env_flag("LANDING_PAGE")).unwrap_or(true),
+        listing_walk_timeout: env::var("LISTING_WALK_TIMEOUT_MS").ok().and_then(|s| s.parse().ok()).filter(|ms| *ms > 0).map(Duration::from_millis),
+        generations: Arc::new(DashMap::new()),
+        boot_id: format!("{:x}", crate::util::rand_u32()),
+        node_list_max: env::var("NODE_LIST_MAX").ok().and_then(|s| s.parse().ok()).filter(|n| *n > 0).unwrap_or(500),
+        max_connections,
+        connections: Arc::new(Semaphore::new(if max_connections == 0 { Semaphore::MAX_PERMITS } else { max_connections })),
+        max_upload_bytes,
+        min_free_bytes: env::var("MIN_FREE_BYTES").ok().and_then(|s| s.trim().parse().ok()).unwrap_or(0),
+        log_rotate_bytes: env::var("LOG_ROTATE_BYTES").ok().and_then(|s| s.parse().ok()).filter(|n| *n > 0),
+        max_filename_bytes,
+        max_content_type_bytes,
+        require_content_type: env_flag("REQUIRE_CONTENT_TYPE"),
+        file_locks: Arc::new(DashMap::new()),
+        cold,
+        compression_max_bytes: env::var("COMPRESSION_MAX_BYTES").ok().and_then(|s| s.parse().ok()).unwrap_or(256 * 1024 * 1024),
+        amplification: Arc::new(AmplificationCounters::default()),
+        metrics: None,
+        rehydrating: Arc::new(DashMap::new()),
+    })
 }
 
 pub fn build_redis_url() -> Option<String> {
@@ -34,6 +163,64 @@ pub fn build_redis_url() -> Option<String> {
     }
 }
 
+impl AppState {
+    /// Directory backing `bucket`: its `BUCKET_PATHS` entry, else `root_dir/<bucket>`.
+    /// `None` when the name could step outside its parent directory.
+    pub fn resolve_bucket_path(&self, bucket: &str) -> Option<PathBuf> {
+        match self.bucket_paths.get(bucket) {
+            Some(dir) => Some(dir.clone()),
+            None => safe_bucket_dir(&self.root_dir, bucket),
+        }
+    }
+
+    /// Path of `filename` inside `bucket`, with the same checks as `resolve_bucket_path`;
+    /// `filename` may be a nested key such as `images/logo.png`.
+    /// In content-addressed mode a hash resolves to its blob.
+    pub fn resolve_file_path(&self, bucket: &str, filename: &str) -> Option<PathBuf> {
+        if self.content_addressed && is_content_hash(filename) { return Some(blob_path(&self.resolve_bucket_path(bucket)?, filename)); }
+        match self.bucket_paths.get(bucket) {
+            Some(dir) => is_safe_key(filename).then(|| dir.join(filename)),
+            None => safe_join(&self.root_dir, bucket, filename),
+        }
+    }
+
+    /// Requests currently holding a `MAX_CONNECTIONS` permit.
+    pub fn active_connections(&self) -> usize {
+        let capacity = if self.max_connections == 0 { Semaphore::MAX_PERMITS } else { self.max_connections };
+        capacity - self.connections.available_permits()
+    }
+}
+
 pub fn port_from_env() -> u16 {
     env::var("PORT").ok().and_then(|s| s.parse().ok()).unwrap_or(3001)
-}
\ No newline at end of file
+}
+
+pub fn env_flag(name: &str) -> bool {
+    env::var(name).map(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "on")).unwrap_or(false)
+}
+
+/// Parses `ALLOWED_ORIGINS` (`https://a.example,https://b.example`). Origins are compared
+/// verbatim by browsers, so a trailing slash or path is rejected rather than never matching.
+fn parse_allowed_origins(raw: &str) -> anyhow::Result<Vec<axum::http::HeaderValue>> {
+    raw.split(',').map(str::trim).filter(|o| !o.is_empty()).map(|origin| {
+        let valid = origin.split_once("://").is_some_and(|(scheme, host)| !scheme.is_empty() && !host.is_empty() && !host.contains('/'));
+        if !valid { anyhow::bail!("ALLOWED_ORIGINS 中的来源不合法（应为 scheme://host[:port]）: {}", origin); }
+        Ok(axum::http::HeaderValue::from_str(origin)?)
+    }).collect()
+}
+
+/// Parses `BUCKET_PATHS` (`bucket=/abs/dir,other=/abs/dir2`). Every target must be an
+/// existing absolute directory, so a missing mount fails startup instead of silently
+/// falling back to `root_dir`.
+fn parse_bucket_paths(raw: &str) -> anyhow::Result<HashMap<String, PathBuf>> {
+    let mut paths = HashMap::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let Some((bucket, dir)) = entry.split_once('=') else { anyhow::bail!("BUCKET_PATHS 条目格式应为 bucket=/path: {}", entry) };
+        let (bucket, dir) = (bucket.trim(), PathBuf::from(dir.trim()));
+        if !is_plain_name(bucket) { anyhow::bail!("BUCKET_PATHS 中的储存桶名称不合法: {}", bucket); }
+        if !dir.is_absolute() { anyhow::bail!("BUCKET_PATHS 中的路径必须是绝对路径: {}", dir.display()); }
+        if !dir.is_dir() { anyhow::bail!("BUCKET_PATHS 中的路径不存在或不是目录: {}", dir.display()); }
+        if paths.insert(bucket.to_string(), dir).is_some() { anyhow::bail!("BUCKET_PATHS 中的储存桶重复: {}", bucket); }
+    }
+    Ok(paths)
+}
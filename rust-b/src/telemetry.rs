@@ -0,0 +1,136 @@
+//! Prometheus metrics served on `/metrics`: request counts and latency per route, file
+//! operation counters, and bucket/file gauges computed when scraped.
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use axum::{body::Body, extract::{MatchedPath, State}, http::{Request, StatusCode, header}, response::IntoResponse};
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram, Unit};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+use crate::cas;
+use crate::cold::walk_bucket;
+use crate::state::AppState;
+
+const REQUEST_DURATION: &str = "http_request_duration_seconds";
+
+/// From sub-millisecond metadata lookups up to multi-second transfers.
+const LATENCY_BUCKETS: &[f64] = &[0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Installs the process-wide recorder; call once, before serving.
+pub fn install_recorder() -> anyhow::Result<PrometheusHandle> {
+    let handle = PrometheusBuilder::new().set_buckets_for_metric(Matcher::Full(REQUEST_DURATION.to_string()), LATENCY_BUCKETS)?.install_recorder()?;
+    describe_counter!("http_requests_total", "HTTP requests by method, route and status");
+    describe_histogram!(REQUEST_DURATION, Unit::Seconds, "Time until response headers were ready, by method and route");
+    describe_counter!("fileio_uploads_total", "Files stored by uploads");
+    describe_counter!("fileio_upload_bytes_total", Unit::Bytes, "Bytes stored by uploads");
+    describe_counter!("fileio_downloads_total", "Files served by downloads, including partial ones");
+    describe_counter!("fileio_download_bytes_total", Unit::Bytes, "Bytes of response bodies sent by downloads");
+    describe_counter!("fileio_deletes_total", "Files deleted");
+    describe_gauge!("fileio_buckets", "Buckets on this node");
+    describe_gauge!("fileio_files", "Files on this node, including cold ones");
+    describe_gauge!("fileio_active_connections", "Requests holding a MAX_CONNECTIONS permit");
+    describe_counter!("fileio_compression_skipped_total", "Responses sent uncompressed because they were over COMPRESSION_MAX_BYTES or of unknown size");
+    describe_counter!("fileio_decompression_aborted_total", "Gzip streams rejected for expanding past their expected size");
+    Ok(handle)
+}
+
+pub fn record_upload(bytes: u64) {
+    counter!("fileio_uploads_total").increment(1);
+    counter!("fileio_upload_bytes_total").increment(bytes);
+}
+
+pub fn record_download(bytes: u64) {
+    counter!("fileio_downloads_total").increment(1);
+    counter!("fileio_download_bytes_total").increment(bytes);
+}
+
+pub fn record_delete() {
+    counter!("fileio_deletes_total").increment(1);
+}
+
+/// Counts each request under its route template, so `/files/*path` is one series no
+/// matter how many files exist. Streamed bodies aren't included in the latency.
+pub async fn track_requests(req: Request<Body>, next: axum::middleware::Next) -> axum::response::Response {
+    let endpoint = req.extensions().get::<MatchedPath>().map_or_else(|| "unmatched".to_string(), |p| p.as_str().to_string());
+    let method = req.method().to_string();
+    let start = Instant::now();
+    let resp = next.run(req).await;
+    histogram!(REQUEST_DURATION, "method" => method.clone(), "endpoint" => endpoint.clone()).record(start.elapsed().as_secs_f64());
+    counter!("http_requests_total", "method" => method, "endpoint" => endpoint, "status" => resp.status().as_u16().to_string()).increment(1);
+    resp
+}
+
+pub async fn metrics_endpoint(State(state): State<AppState>) -> impl IntoResponse {
+    let Some(handle) = state.metrics.clone() else { return (StatusCode::NOT_FOUND, axum::Json(serde_json::json!({"error":"指标未启用"}))).into_response() };
+    let worker = state.clone();
+    if let Ok((buckets, files)) = tokio::task::spawn_blocking(move || count_buckets_and_files(&worker)).await {
+        gauge!("fileio_buckets").set(buckets as f64);
+        gauge!("fileio_files").set(files as f64);
+    }
+    gauge!("fileio_active_connections").set(state.active_connections() as f64);
+    counter!("fileio_compression_skipped_total").absolute(state.amplification.compression_skipped.load(Ordering::Relaxed));
+    counter!("fileio_decompression_aborted_total").absolute(state.amplification.decompression_aborted.load(Ordering::Relaxed));
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], handle.render()).into_response()
+}
+
+/// Files are counted the way listings show them: cold markers and content-addressed names
+/// count, other sidecars don't.
+fn count_buckets_and_files(state: &AppState) -> (usize, usize) {
+    let mut dirs: Vec<PathBuf> = state.bucket_paths.values().cloned().collect();
+    if let Ok(iter) = fs::read_dir(&state.root_dir) {
+        dirs.extend(iter.filter_map(Result::ok)
+            .filter(|e| !state.bucket_paths.contains_key(e.file_name().to_string_lossy().as_ref()) && e.path().is_dir())
+            .map(|e| e.path()));
+    }
+    let files = dirs.iter().map(|dir| {
+        let mut stored = 0;
+        walk_bucket(dir, |_| { stored += 1; true });
+        stored + if state.content_addressed { cas::names(dir).len() } else { 0 }
+    }).sum();
+    (dirs.len(), files)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::OnceLock;
+
+    use axum::http::StatusCode;
+    use metrics_exporter_prometheus::PrometheusHandle;
+
+    use super::install_recorder;
+    use crate::testing::{TempRoot, create_bucket, file_part, get, test_state, upload};
+
+    /// The recorder is process-wide, so every test shares one.
+    fn recorder() -> PrometheusHandle {
+        static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+        HANDLE.get_or_init(|| install_recorder().expect("install recorder")).clone()
+    }
+
+    fn sample(exposition: &str, series: &str) -> f64 {
+        exposition.lines().find_map(|l| l.strip_prefix(series)?.strip_prefix(' ')?.parse().ok()).unwrap_or(0.0)
+    }
+
+    #[tokio::test]
+    async fn uploads_are_counted() {
+        let root = TempRoot::new();
+        let mut state = test_state(root.path());
+        state.metrics = Some(recorder());
+        create_bucket(&state, "docs").await;
+        let before = sample(&String::from_utf8(get(&state, "/metrics").await.body).unwrap(), "fileio_uploads_total");
+        upload(&state, "/api/buckets/docs/upload", &[file_part("a.txt", b"a"), file_part("b.txt", b"b")]).await;
+        let resp = get(&state, "/metrics").await;
+        assert_eq!(resp.status, StatusCode::OK);
+        let exposition = String::from_utf8(resp.body).unwrap();
+        // Tests run in parallel and share the recorder, so others may have added to it.
+        assert!(sample(&exposition, "fileio_uploads_total") >= before + 2.0, "{}", exposition);
+        assert_eq!(sample(&exposition, "fileio_files"), 2.0);
+    }
+
+    #[tokio::test]
+    async fn metrics_are_absent_without_a_recorder() {
+        let root = TempRoot::new();
+        assert_eq!(get(&test_state(root.path()), "/metrics").await.status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,161 @@
+//! Helpers for the handler tests: a state rooted in a scratch directory, and requests
+//! driven through the full router the way a client would send them.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::http::{HeaderMap, Request, StatusCode};
+use dashmap::DashMap;
+use tokio::sync::Semaphore;
+use tower::ServiceExt;
+
+use crate::amplify::AmplificationCounters;
+use crate::routes::build_app;
+use crate::state::AppState;
+use crate::util::rand_u32;
+
+/// A fresh directory under the system temp dir, removed again on drop.
+pub struct TempRoot(PathBuf);
+
+impl TempRoot {
+    pub fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("fileio-test-{}-{:08x}{:08x}", std::process::id(), rand_u32(), rand_u32()));
+        std::fs::create_dir_all(&dir).expect("create test root");
+        TempRoot(dir)
+    }
+
+    pub fn path(&self) -> &Path { &self.0 }
+}
+
+impl Drop for TempRoot {
+    fn drop(&mut self) { let _ = std::fs::remove_dir_all(&self.0); }
+}
+
+/// Every option at its default, without Redis or an index; tests flip what they need.
+pub fn test_state(root: &Path) -> AppState {
+    AppState {
+        root_dir: root.to_path_buf(),
+        bucket_paths: Arc::new(HashMap::new()),
+        api_key: None,
+        api_keys: Arc::new(Vec::new()),
+        rate_limits: Arc::new(DashMap::new()),
+        rate_limit_rpm: 0,
+        signing_secret: None,
+        redis: None,
+        index: None,
+        public_host: "localhost".to_string(),
+        node_id: "localhost:3001".to_string(),
+        allowed_origins: Arc::new(Vec::new()),
+        proxy_mode: false,
+        http: reqwest::Client::new(),
+        clean_names: false,
+        content_addressed: false,
+        s3_compat_names: false,
+        name_reservation: false,
+        quarantine_bucket: None,
+        protected_buckets: Arc::new(HashSet::new()),
+        prune_min_age: Duration::from_secs(3600),
+        bucket_writers: Arc::new(DashMap::new()),
+        uploads: Arc::new(DashMap::new()),
+        cache_control: "no-store".to_string(),
+        directory_index: false,
+        landing_page: true,
+        listing_walk_timeout: None,
+        generations: Arc::new(DashMap::new()),
+        boot_id: "test".to_string(),
+        node_list_max: 500,
+        max_connections: 0,
+        connections: Arc::new(Semaphore::new(Semaphore::MAX_PERMITS)),
+        max_upload_bytes: 100 * 1024 * 1024,
+        min_free_bytes: 0,
+        log_rotate_bytes: None,
+        max_filename_bytes: 255,
+        max_content_type_bytes: 255,
+        require_content_type: false,
+        file_locks: Arc::new(DashMap::new()),
+        cold: None,
+        compression_max_bytes: 256 * 1024 * 1024,
+        amplification: Arc::new(AmplificationCounters::default()),
+        metrics: None,
+        rehydrating: Arc::new(DashMap::new()),
+    }
+}
+
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).unwrap_or_else(|e| panic!("not JSON ({}): {}", e, String::from_utf8_lossy(&self.body)))
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> { self.headers.get(name).and_then(|v| v.to_str().ok()) }
+}
+
+/// Runs one request through `build_app`, trailing-slash handling included.
+pub async fn send(state: &AppState, req: Request<Body>) -> TestResponse {
+    let resp = build_app(state.clone()).oneshot(req).await.expect("router is infallible");
+    let (parts, body) = resp.into_parts();
+    let body = axum::body::to_bytes(body, usize::MAX).await.expect("read body").to_vec();
+    TestResponse { status: parts.status, headers: parts.headers, body }
+}
+
+pub async fn get(state: &AppState, uri: &str) -> TestResponse {
+    send(state, Request::get(uri).body(Body::empty()).unwrap()).await
+}
+
+pub async fn post_json(state: &AppState, uri: &str, body: serde_json::Value) -> TestResponse {
+    send(state, Request::post(uri).header("content-type", "application/json").body(Body::from(body.to_string())).unwrap()).await
+}
+
+pub async fn create_bucket(state: &AppState, name: &str) {
+    let resp = post_json(state, "/api/buckets", serde_json::json!({"name": name})).await;
+    assert_eq!(resp.status, StatusCode::OK, "{}", String::from_utf8_lossy(&resp.body));
+}
+
+/// One multipart part; `name` and `filename` are left out of the header when `None`.
+pub struct Part<'a> {
+    pub name: Option<&'a str>,
+    pub filename: Option<&'a str>,
+    pub data: &'a [u8],
+}
+
+/// `Part { name: Some("file"), .. }`, the usual upload field.
+pub fn file_part<'a>(filename: &'a str, data: &'a [u8]) -> Part<'a> {
+    Part { name: Some("file"), filename: Some(filename), data }
+}
+
+pub fn multipart_request(uri: &str, parts: &[Part<'_>]) -> Request<Body> {
+    const BOUNDARY: &str = "fileio-test-boundary";
+    let mut body = Vec::new();
+    for part in parts {
+        let mut disposition = "form-data".to_string();
+        if let Some(name) = part.name { disposition.push_str(&format!("; name=\"{}\"", name)); }
+        if let Some(filename) = part.filename { disposition.push_str(&format!("; filename=\"{}\"", filename)); }
+        body.extend_from_slice(format!("--{}\r\nContent-Disposition: {}\r\n\r\n", BOUNDARY, disposition).as_bytes());
+        body.extend_from_slice(part.data);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+    Request::post(uri).header("content-type", format!("multipart/form-data; boundary={}", BOUNDARY)).body(Body::from(body)).unwrap()
+}
+
+pub async fn upload(state: &AppState, uri: &str, parts: &[Part<'_>]) -> TestResponse {
+    send(state, multipart_request(uri, parts)).await
+}
+
+/// Names of the visible files directly in `dir`, sorted.
+pub fn visible_files(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(dir).map(|iter| iter.filter_map(Result::ok)
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .filter(|n| !n.starts_with('.'))
+        .collect()).unwrap_or_default();
+    names.sort();
+    names
+}
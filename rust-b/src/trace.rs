@@ -0,0 +1,63 @@
+use axum::{body::Body, http::{HeaderMap, HeaderValue, Request}, response::Response};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// Correlation ids for one inbound request, stored in request extensions by `propagate_trace`.
+#[derive(Clone)]
+pub struct TraceContext {
+    pub request_id: String,
+    /// 32 hex chars; taken from an inbound `traceparent` or generated.
+    pub trace_id: String,
+    /// The inbound parent span id, if the caller sent a valid `traceparent`.
+    pub parent_id: Option<String>,
+    pub flags: String,
+}
+
+impl TraceContext {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let parent = headers.get(TRACEPARENT_HEADER).and_then(|v| v.to_str().ok()).and_then(parse_traceparent);
+        let request_id = headers.get(REQUEST_ID_HEADER).and_then(|v| v.to_str().ok()).map(str::trim)
+            .filter(|v| !v.is_empty() && v.len() <= 128 && v.bytes().all(|b| b.is_ascii_graphic()))
+            .map(str::to_string);
+        let (trace_id, parent_id, flags) = match parent {
+            Some((trace, span, flags)) => (trace, Some(span), flags),
+            None => (random_hex(16), None, "01".to_string()),
+        };
+        let request_id = request_id.unwrap_or_else(|| trace_id.clone());
+        TraceContext { request_id, trace_id, parent_id, flags }
+    }
+
+    /// Headers to attach to an outbound call made while serving this request.
+    /// Each call gets a fresh span id under the same trace id.
+    pub fn outbound_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Ok(v) = HeaderValue::from_str(&self.request_id) { headers.insert(REQUEST_ID_HEADER, v); }
+        if let Ok(v) = HeaderValue::from_str(&format!("00-{}-{}-{}", self.trace_id, random_hex(8), self.flags)) { headers.insert(TRACEPARENT_HEADER, v); }
+        headers
+    }
+}
+
+/// W3C `traceparent`: `00-<32 hex trace id>-<16 hex span id>-<2 hex flags>`; all-zero ids are invalid.
+fn parse_traceparent(value: &str) -> Option<(String, String, String)> {
+    let mut parts = value.trim().split('-');
+    let (version, trace, span, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    let hex = |s: &str, len: usize| s.len() == len && s.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+    if version != "00" || parts.next().is_some() || !hex(trace, 32) || !hex(span, 16) || !hex(flags, 2) { return None; }
+    if trace.bytes().all(|b| b == b'0') || span.bytes().all(|b| b == b'0') { return None; }
+    Some((trace.to_string(), span.to_string(), flags.to_string()))
+}
+
+fn random_hex(bytes: usize) -> String {
+    (0..bytes.div_ceil(4)).map(|_| format!("{:08x}", crate::util::rand_u32())).collect::<String>()[..bytes * 2].to_string()
+}
+
+/// Extracts or generates the request's trace context and echoes `X-Request-Id` on the response.
+pub async fn propagate_trace(mut req: Request<Body>, next: axum::middleware::Next) -> Response {
+    let ctx = TraceContext::from_headers(req.headers());
+    let request_id = HeaderValue::from_str(&ctx.request_id).ok();
+    req.extensions_mut().insert(ctx);
+    let mut resp = next.run(req).await;
+    if let Some(v) = request_id { resp.headers_mut().insert(REQUEST_ID_HEADER, v); }
+    resp
+}
@@ -1,4 +1,4 @@
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 use std::fs;
 
 pub fn ensure_dir(path: &Path) -> anyhow::Result<()> {
@@ -8,15 +8,251 @@ pub fn ensure_dir(path: &Path) -> anyhow::Result<()> {
     Ok(())
 }
 
-pub fn format_time(t: Option<std::time::SystemTime>) -> String {
-    match t.and_then(|x| x.duration_since(std::time::UNIX_EPOCH).ok()) {
-        Some(d) => format!("{}", d.as_secs()),
-        None => "0".into(),
-    }
+/// RFC 3339 in UTC with whole seconds (`2024-06-10T15:00:03Z`); `None` (JSON `null`) when
+/// the platform didn't report the time.
+pub fn format_time(t: Option<std::time::SystemTime>) -> Option<String> {
+    t.map(|t| chrono::DateTime::<chrono::Utc>::from(t).to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
 }
 
 pub fn rand_u32() -> u32 {
     use rand::RngCore;
     let mut rng = rand::rngs::OsRng;
     rng.next_u32()
-}
\ No newline at end of file
+}
+
+/// Splits a stored `timestamp-rand-original` name into its original part.
+/// Names that don't carry the generated prefix are returned unchanged.
+pub fn strip_unique_prefix(name: &str) -> &str {
+    let mut parts = name.splitn(3, '-');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(ts), Some(rnd), Some(rest))
+            if !ts.is_empty() && !rnd.is_empty() && !rest.is_empty()
+                && ts.bytes().all(|b| b.is_ascii_digit()) && rnd.bytes().all(|b| b.is_ascii_digit()) => rest,
+        _ => name,
+    }
+}
+
+/// Millisecond timestamp embedded in a stored name, if any.
+pub fn unique_timestamp(name: &str) -> Option<i64> {
+    if strip_unique_prefix(name).len() == name.len() { return None; }
+    name.split('-').next().and_then(|ts| ts.parse().ok())
+}
+
+/// Dot-prefixed entries are sidecars (bucket config, metadata) rather than stored files.
+pub fn is_hidden(name: &str) -> bool {
+    name.starts_with('.')
+}
+
+/// Escapes text for HTML element content and quoted attribute values.
+pub fn html_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Percent-encodes a single URL path segment, leaving only RFC 3986 unreserved bytes as-is.
+pub fn percent_encode_segment(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') { out.push(b as char); } else { out.push_str(&format!("%{:02X}", b)); }
+    }
+    out
+}
+
+/// Percent-encodes each segment of a key, keeping the `/` between them.
+pub fn percent_encode_key(key: &str) -> String {
+    key.split('/').map(percent_encode_segment).collect::<Vec<_>>().join("/")
+}
+
+/// Content type recognised from a file's leading bytes, for uploads that didn't declare one.
+pub fn sniff_content_type(head: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"(\xb5/\xfd", "application/zstd"),
+        (b"BZh", "application/x-bzip2"),
+        (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+        (b"OggS", "audio/ogg"),
+        (b"fLaC", "audio/flac"),
+        (b"ID3", "audio/mpeg"),
+        (b"\x1a\x45\xdf\xa3", "video/webm"),
+        (b"\x00asm", "application/wasm"),
+    ];
+    if let Some((_, ct)) = SIGNATURES.iter().find(|(sig, _)| head.starts_with(sig)) { return Some(ct); }
+    if head.len() >= 12 && &head[..4] == b"RIFF" {
+        match &head[8..12] { b"WEBP" => return Some("image/webp"), b"WAVE" => return Some("audio/wav"), _ => {} }
+    }
+    if head.len() >= 12 && &head[4..8] == b"ftyp" { return Some("video/mp4"); }
+    None
+}
+
+/// A single path segment without separators, parent references or drive prefixes.
+pub fn is_plain_name(name: &str) -> bool {
+    if name.is_empty() || name == "." || name.contains("..") || name.contains(['/', '\\', '\0']) { return false; }
+    // `C:foo` is a drive-relative path on Windows.
+    if name.len() >= 2 && name.as_bytes()[1] == b':' && name.as_bytes()[0].is_ascii_alphabetic() { return false; }
+    let mut components = Path::new(name).components();
+    matches!((components.next(), components.next()), (Some(Component::Normal(_)), None))
+}
+
+/// `root/bucket`, or `None` when the bucket name could step outside `root`.
+pub fn safe_bucket_dir(root: &Path, bucket: &str) -> Option<PathBuf> {
+    is_plain_name(bucket).then(|| root.join(bucket))
+}
+
+/// Rules for names accepted by `POST /api/buckets`. With `s3_compat` the S3 bucket-naming
+/// rules are layered on top; dots are already excluded, so the dot and IP-address rules hold
+/// automatically. The error is the message to return to the client.
+pub fn validate_bucket_name(name: &str, s3_compat: bool) -> Result<(), &'static str> {
+    if name.is_empty() { return Err("储存桶名称不能为空"); }
+    let valid = name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') && !name.starts_with('-') && !name.ends_with('-');
+    if !valid { return Err("储存桶名称只能包含小写字母、数字和连字符，且不能以连字符开头或结尾"); }
+    if !s3_compat { return Ok(()); }
+    if !(3..=63).contains(&name.len()) { return Err("储存桶名称长度必须在 3 到 63 个字符之间"); }
+    if name.starts_with("xn--") || name.starts_with("sthree-") { return Err("储存桶名称不能以 xn-- 或 sthree- 开头"); }
+    if name.ends_with("-s3alias") || name.ends_with("--ol-s3") { return Err("储存桶名称不能以 -s3alias 或 --ol-s3 结尾"); }
+    Ok(())
+}
+
+/// `root/bucket/key`, or `None` when the bucket or any segment of the key could step
+/// outside its parent.
+pub fn safe_join(root: &Path, bucket: &str, key: &str) -> Option<PathBuf> {
+    if !is_safe_key(key) { return None; }
+    safe_bucket_dir(root, bucket).map(|dir| dir.join(key))
+}
+
+/// A file key within a bucket: plain names joined by `/`, as in `images/2024/logo.png`.
+/// Folder segments may not be hidden, so `.cas/` and other internal directories stay
+/// unreachable; a hidden last segment is left for `resolve_stored_name` to refuse.
+pub fn is_safe_key(key: &str) -> bool {
+    // A leading `/` would leave an empty folder and make the joined path absolute.
+    if key.starts_with('/') { return false; }
+    let (folder, leaf) = split_key(key);
+    is_plain_name(leaf) && (folder.is_empty() || folder.split('/').all(|s| is_plain_name(s) && !is_hidden(s)))
+}
+
+/// `(folder, name)` of a key; the folder is empty for files at the top of the bucket.
+pub fn split_key(key: &str) -> (&str, &str) {
+    key.rsplit_once('/').unwrap_or(("", key))
+}
+
+pub fn join_key(folder: &str, name: &str) -> String {
+    if folder.is_empty() { name.to_string() } else { format!("{}/{}", folder, name) }
+}
+
+/// `strip_unique_prefix` applied to the last segment of a key, keeping its folder.
+pub fn original_key(key: &str) -> String {
+    let (folder, name) = split_key(key);
+    join_key(folder, strip_unique_prefix(name))
+}
+
+/// `.<name><suffix>` beside the file a key names, so nested files keep their sidecars in
+/// their own folder.
+pub fn sidecar_path(bucket_dir: &Path, key: &str, suffix: &str) -> PathBuf {
+    let (folder, name) = split_key(key);
+    bucket_dir.join(folder).join(format!(".{}{}", name, suffix))
+}
+
+/// Most ranges honoured in one `Range` header; longer lists are ignored and the whole file
+/// is served, so a client can't make us emit thousands of tiny (or overlapping) parts.
+pub const MAX_BYTE_RANGES: usize = 16;
+
+pub enum ByteRange {
+    /// Inclusive `start..=end`, already clamped to the file length.
+    Satisfiable(u64, u64),
+    /// Two or more satisfiable ranges, in request order, for a `multipart/byteranges` reply.
+    Multiple(Vec<(u64, u64)>),
+    Unsatisfiable,
+}
+
+/// Parses a `Range: bytes=...` header against a file of `len` bytes.
+/// `None` means the header is ignored (other units, bad syntax, more than `MAX_BYTE_RANGES`
+/// ranges) and the whole file should be served, as RFC 9110 allows. Ranges starting past
+/// the end are dropped; if none remain the header is unsatisfiable.
+pub fn parse_byte_range(value: &str, len: u64) -> Option<ByteRange> {
+    let specs: Vec<&str> = value.trim().strip_prefix("bytes=")?.split(',').map(str::trim).filter(|s| !s.is_empty()).collect();
+    if specs.is_empty() || specs.len() > MAX_BYTE_RANGES { return None; }
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for spec in specs {
+        if let Some(r) = parse_range_spec(spec, len)? { ranges.push(r); }
+    }
+    match ranges.len() {
+        0 => Some(ByteRange::Unsatisfiable),
+        1 => Some(ByteRange::Satisfiable(ranges[0].0, ranges[0].1)),
+        _ => Some(ByteRange::Multiple(ranges)),
+    }
+}
+
+/// One `first-last` / `first-` / `-suffix` spec: `None` on bad syntax, `Some(None)` when unsatisfiable.
+fn parse_range_spec(spec: &str, len: u64) -> Option<Option<(u64, u64)>> {
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    if start.is_empty() {
+        // Suffix range: the last `n` bytes.
+        let n: u64 = end.parse().ok()?;
+        if n == 0 || len == 0 { return Some(None); }
+        return Some(Some((len - n.min(len), len - 1)));
+    }
+    let start: u64 = start.parse().ok()?;
+    let end: Option<u64> = if end.is_empty() { None } else { Some(end.parse().ok()?) };
+    if end.is_some_and(|e| e < start) { return None; }
+    if start >= len { return Some(None); }
+    Some(Some((start, end.map_or(len - 1, |e| e.min(len - 1)))))
+}
+
+/// `Content-Disposition` carrying `name` both as a quoted ASCII fallback and as an
+/// RFC 5987 `filename*` so non-ASCII names survive in browsers that support it.
+pub fn content_disposition(disposition: &str, name: &str) -> axum::http::HeaderValue {
+    let fallback: String = name.chars().map(|c| if c == ' ' || (c.is_ascii_graphic() && c != '"' && c != '\\') { c } else { '_' }).collect();
+    let value = format!("{}; filename=\"{}\"; filename*=UTF-8''{}", disposition, fallback, percent_encode_segment(name));
+    axum::http::HeaderValue::from_str(&value).unwrap_or_else(|_| axum::http::HeaderValue::from_static("attachment"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{format_time, is_safe_key, safe_join};
+
+    #[test]
+    fn times_are_rfc3339_utc_in_whole_seconds() {
+        let t = UNIX_EPOCH + Duration::from_millis(1_718_031_603_750);
+        assert_eq!(format_time(Some(t)).as_deref(), Some("2024-06-10T15:00:03Z"));
+        assert_eq!(format_time(Some(UNIX_EPOCH)).as_deref(), Some("1970-01-01T00:00:00Z"));
+        assert_eq!(format_time(None), None);
+    }
+
+    #[test]
+    fn safe_join_stays_inside_the_bucket() {
+        let root = Path::new("/srv/files");
+        assert_eq!(safe_join(root, "docs", "a.txt"), Some(root.join("docs/a.txt")));
+        assert_eq!(safe_join(root, "docs", "2024/06/a.txt"), Some(root.join("docs/2024/06/a.txt")));
+        for key in ["../secret.txt", "../../etc/passwd", "a/../../b", "..", ".", "..\\secret.txt", "a\\b", "/etc/passwd", "/a.txt", "C:secret.txt", "a//b", "a/", ".cas/blob", ""] {
+            assert_eq!(safe_join(root, "docs", key), None, "{:?}", key);
+        }
+        for bucket in ["..", "../docs", "/docs", "a\\b", ""] {
+            assert_eq!(safe_join(root, bucket, "a.txt"), None, "{:?}", bucket);
+        }
+    }
+
+    #[test]
+    fn hidden_leaf_is_left_to_the_caller() {
+        assert!(is_safe_key(".hidden"));
+        assert!(!is_safe_key(".hidden/a.txt"));
+    }
+}